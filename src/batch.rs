@@ -0,0 +1,370 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use crate::credentials::Credentials;
+use crate::error::MyError;
+use crate::output::{ErrorReport, OperationReport, Reporter};
+use crate::pelican::PelicanInfo;
+use crate::transfer::{Transfer, TransferPlan, TransferResult, Verb, build_http_client};
+
+#[derive(Debug, PartialEq)]
+pub struct ManifestEntry {
+    pub url: String,
+    pub path: String,
+}
+
+/// Parse `<url> <local path>` lines, skipping blank lines and `#` comments.
+///
+/// The local path is the rest of the line, so it may contain spaces.
+pub fn parse_manifest(data: &str) -> Result<Vec<ManifestEntry>, Box<dyn Error>> {
+    let mut ret = Vec::new();
+    for (i, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(char::is_whitespace) {
+            Some((url, path)) if !path.trim().is_empty() => ret.push(ManifestEntry {
+                url: url.to_string(),
+                path: path.trim().to_string(),
+            }),
+            _ => {
+                return Err(Box::new(MyError::Transfer(format!(
+                    "manifest line {}: expected `<url> <local path>`",
+                    i + 1
+                ))));
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// A transfer that is ready to run, with its origin and a client holding the right token.
+struct Job<'a> {
+    transfer: Transfer,
+    origin: &'a PelicanInfo,
+    http_client: reqwest::blocking::Client,
+}
+
+/// Find an already looked-up namespace that contains `url`.
+fn find_origin<'a>(origins: &'a [PelicanInfo], url: &str) -> Option<&'a PelicanInfo> {
+    origins.iter().find(|info| {
+        url.strip_prefix(info.get_osdf_prefix())
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    })
+}
+
+/// Run the jobs on `parallel` threads, returning the results in job order.
+fn run_jobs(jobs: &[Job], parallel: usize) -> Vec<Result<TransferResult, ErrorReport>> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(vec![None; jobs.len()]);
+    thread::scope(|s| {
+        for _ in 0..parallel.clamp(1, jobs.len().max(1)) {
+            s.spawn(|| {
+                loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    let job = match jobs.get(i) {
+                        Some(job) => job,
+                        None => break,
+                    };
+                    log::info!("starting transfer of {}", job.transfer.url);
+                    let result = job
+                        .transfer
+                        .execute_with_client(job.origin, &job.http_client)
+                        .map_err(|e| ErrorReport::new(e.as_ref()));
+                    results.lock().unwrap()[i] = Some(result);
+                }
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|r| r.expect("every job ran"))
+        .collect()
+}
+
+fn make_job<'a>(
+    creds: &Credentials,
+    entry: &ManifestEntry,
+    mode: Verb,
+    origins: &'a [PelicanInfo],
+    clients: &mut HashMap<String, reqwest::blocking::Client>,
+) -> Result<Job<'a>, Box<dyn Error>> {
+    let origin = find_origin(origins, &entry.url)
+        .ok_or_else(|| MyError::Pelican("namespace lookup failed".into()))?;
+    if mode == Verb::Get
+        && let Some(parent) = Path::new(&entry.path).parent()
+        && !parent.as_os_str().is_empty()
+    {
+        fs::create_dir_all(parent)?;
+    }
+    let transfer = Transfer::new(entry.url.clone(), entry.path.clone(), mode);
+    let cred = creds.get_correct_cred(&transfer, origin)?;
+    let http_client = match clients.get(&cred.access_token) {
+        Some(client) => client.clone(),
+        None => {
+            let client = build_http_client(cred)?;
+            clients.insert(cred.access_token.clone(), client.clone());
+            client
+        }
+    };
+    Ok(Job {
+        transfer,
+        origin,
+        http_client,
+    })
+}
+
+/// Transfer every manifest entry, with one director lookup per namespace
+/// and one HTTP client per credential.
+pub fn run_batch(
+    creds: &Credentials,
+    entries: &[ManifestEntry],
+    mode: Verb,
+    parallel: usize,
+    report: &Reporter,
+) -> Result<(), Box<dyn Error>> {
+    // look up each namespace once
+    let mut origins: Vec<PelicanInfo> = Vec::new();
+    let mut setup_errors: HashMap<usize, ErrorReport> = HashMap::new();
+    for (i, entry) in entries.iter().enumerate() {
+        if find_origin(&origins, &entry.url).is_none() {
+            match PelicanInfo::from_url(&entry.url) {
+                Ok(info) => origins.push(info),
+                Err(e) => {
+                    setup_errors.insert(i, ErrorReport::new(e.as_ref()));
+                }
+            }
+        }
+    }
+
+    let mut clients: HashMap<String, reqwest::blocking::Client> = HashMap::new();
+    let mut jobs = Vec::new();
+    for (i, entry) in entries.iter().enumerate() {
+        if setup_errors.contains_key(&i) {
+            continue;
+        }
+        match make_job(creds, entry, mode, &origins, &mut clients) {
+            Ok(job) => jobs.push(job),
+            Err(e) => {
+                setup_errors.insert(i, ErrorReport::new(e.as_ref()));
+            }
+        }
+    }
+
+    // jobs are in entry order, skipping the entries that failed setup
+    let mut job_results = run_jobs(&jobs, parallel).into_iter();
+    let results: Vec<_> = (0..entries.len())
+        .map(|i| match setup_errors.remove(&i) {
+            Some(e) => Err(e),
+            None => job_results.next().expect("one result per job"),
+        })
+        .collect();
+
+    print_summary(entries, &results, mode, report)
+}
+
+/// Print where a transfer would go, and with which credential.
+pub fn print_plan(plan: &TransferPlan, report: &Reporter) -> Result<(), Box<dyn Error>> {
+    if report.is_json() {
+        return report.json(plan);
+    }
+    println!("{} {}", plan.verb, plan.url);
+    println!("  origin:     {}", plan.origin);
+    println!("  final url:  {}", plan.origin_url);
+    println!(
+        "  credential: {}",
+        plan.credential.as_deref().unwrap_or("<unknown source>")
+    );
+    println!("  scopes:     {}", plan.scopes.join(" "));
+    Ok(())
+}
+
+/// Resolve each transfer to an origin url and credential, and print that instead of transferring.
+pub fn dry_run(
+    creds: &Credentials,
+    transfers: &[Transfer],
+    report: &Reporter,
+) -> Result<(), Box<dyn Error>> {
+    let mut origins: Vec<PelicanInfo> = Vec::new();
+    let mut failed = 0;
+    for transfer in transfers.iter() {
+        let mut plan = || -> Result<TransferPlan, Box<dyn Error>> {
+            if find_origin(&origins, &transfer.url).is_none() {
+                origins.push(PelicanInfo::from_url(&transfer.url)?);
+            }
+            let origin = find_origin(&origins, &transfer.url)
+                .ok_or_else(|| MyError::Pelican("namespace lookup failed".into()))?;
+            transfer.plan(creds, origin)
+        };
+        match plan() {
+            Ok(plan) => print_plan(&plan, report)?,
+            Err(e) => {
+                if report.is_json() {
+                    report.json(&OperationReport::new(
+                        Some(&transfer.url),
+                        None,
+                        &format!("{:?}", transfer.mode).to_lowercase(),
+                        Err(ErrorReport::new(e.as_ref())),
+                    ))?;
+                } else {
+                    println!("failed {}: {}", transfer.url, e);
+                }
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(Box::new(MyError::Transfer(format!(
+            "{} of {} urls failed to resolve",
+            failed,
+            transfers.len()
+        ))));
+    }
+    Ok(())
+}
+
+fn print_summary(
+    entries: &[ManifestEntry],
+    results: &[Result<TransferResult, ErrorReport>],
+    mode: Verb,
+    report: &Reporter,
+) -> Result<(), Box<dyn Error>> {
+    let verb = format!("{:?}", mode).to_lowercase();
+    let mut failed = 0;
+    for (entry, result) in entries.iter().zip(results.iter()) {
+        if result.is_err() {
+            failed += 1;
+        }
+        if report.is_json() {
+            report.json(&OperationReport::new(
+                Some(&entry.url),
+                Some(&entry.path),
+                &verb,
+                result.as_ref().map(Some).map_err(Clone::clone),
+            ))?;
+            continue;
+        }
+        match result {
+            Ok(result) => {
+                println!("ok     {} {}", entry.url, entry.path);
+                if report.stats {
+                    println!("{}", serde_json::to_string(result)?);
+                }
+            }
+            Err(e) => {
+                println!("failed {} {}: {}", entry.url, entry.path, e.message);
+            }
+        }
+    }
+    if !report.is_json() {
+        println!("{} succeeded, {} failed", entries.len() - failed, failed);
+    }
+
+    if failed > 0 {
+        return Err(Box::new(MyError::Transfer(format!(
+            "{} of {} files failed",
+            failed,
+            entries.len()
+        ))));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use httpmock::prelude::*;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::logging::{test_cred, test_logger};
+
+    #[test]
+    fn test_parse_manifest() {
+        let entries = parse_manifest(
+            "# comment\nosdf:///ns/a.txt  a.txt\n\nosdf:///ns/b.txt\tdir/with space/b.txt\n",
+        )
+        .unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                ManifestEntry {
+                    url: "osdf:///ns/a.txt".into(),
+                    path: "a.txt".into()
+                },
+                ManifestEntry {
+                    url: "osdf:///ns/b.txt".into(),
+                    path: "dir/with space/b.txt".into()
+                },
+            ]
+        );
+        assert!(parse_manifest("osdf:///ns/a.txt\n").is_err());
+    }
+
+    #[test]
+    fn test_find_origin() {
+        let origins = vec![PelicanInfo {
+            origins: vec!["http://origin/ns".into()],
+            osdf_prefix: "osdf:///ns".into(),
+        }];
+        assert!(find_origin(&origins, "osdf:///ns/a.txt").is_some());
+        assert!(find_origin(&origins, "osdf:///nsx/a.txt").is_none());
+    }
+
+    #[test]
+    fn test_run_jobs() {
+        test_logger();
+
+        let server = MockServer::start();
+        let good = server.mock(|when, then| {
+            when.method(GET).path_prefix("/ns/good");
+            then.status(200).body("data");
+        });
+        let missing = server.mock(|when, then| {
+            when.method(GET).path("/ns/missing");
+            then.status(404);
+        });
+
+        let cred = test_cred(&["storage.read:/"]);
+        let http_client = build_http_client(&cred).unwrap();
+        let origin = PelicanInfo {
+            origins: vec![server.url("/ns")],
+            osdf_prefix: "osdf:///ns".into(),
+        };
+
+        let tmp_dir = TempDir::new().unwrap();
+        let mut jobs = Vec::new();
+        for name in ["good1", "missing", "good2", "good3"] {
+            jobs.push(Job {
+                transfer: Transfer::new(
+                    format!("osdf:///ns/{}", name),
+                    tmp_dir.path().join(name).to_string_lossy().into_owned(),
+                    Verb::Get,
+                ),
+                origin: &origin,
+                http_client: http_client.clone(),
+            });
+        }
+
+        let results = run_jobs(&jobs, 3);
+        assert_eq!(results.len(), 4);
+        assert!(results[0].is_ok());
+        assert!(results[1].as_ref().unwrap_err().message.contains("404"));
+        assert!(results[2].is_ok());
+        assert!(results[3].is_ok());
+        good.assert_calls(3);
+        missing.assert_calls(1);
+        assert_eq!(
+            fs::read_to_string(tmp_dir.path().join("good3")).unwrap(),
+            "data"
+        );
+    }
+}
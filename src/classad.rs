@@ -0,0 +1,334 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::error::MyError;
+
+/// A single ClassAd value, restricted to the literals that show up in
+/// HTCondor file transfer plugin input and output.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Value {
+    Str(String),
+    Int(i64),
+    Real(f64),
+    Bool(bool),
+    Undefined,
+    List(Vec<Value>),
+    Ad(ClassAd),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Str(s) => {
+                write!(f, "\"")?;
+                for c in s.chars() {
+                    match c {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\t' => write!(f, "\\t")?,
+                        c => write!(f, "{c}")?,
+                    }
+                }
+                write!(f, "\"")
+            }
+            Value::Int(n) => write!(f, "{n}"),
+            Value::Real(n) => write!(f, "{n:?}"),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::Undefined => write!(f, "undefined"),
+            Value::List(items) => {
+                write!(f, "{{ ")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                write!(f, " }}")
+            }
+            Value::Ad(ad) => write!(f, "{ad}"),
+        }
+    }
+}
+
+/// An ordered set of attributes. Lookups are case-insensitive, as in HTCondor.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ClassAd(Vec<(String, Value)>);
+
+impl ClassAd {
+    pub fn new() -> Self {
+        ClassAd(Vec::new())
+    }
+
+    pub fn insert(&mut self, key: &str, value: Value) {
+        match self.0.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(key)) {
+            Some((_, v)) => *v = value,
+            None => self.0.push((key.to_string(), value)),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.0
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v)
+    }
+
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        match self.get(key) {
+            Some(Value::Str(s)) => Some(s.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Render in the "old" line-oriented format, one attribute per line.
+    pub fn to_old_format(&self) -> String {
+        let mut ret = String::new();
+        for (k, v) in self.0.iter() {
+            ret.push_str(&format!("{k} = {v}\n"));
+        }
+        ret
+    }
+}
+
+impl fmt::Display for ClassAd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[ ")?;
+        for (k, v) in self.0.iter() {
+            write!(f, "{k} = {v}; ")?;
+        }
+        write!(f, "]")
+    }
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+fn parse_err(msg: &str) -> Box<dyn Error> {
+    Box::new(MyError::Plugin(format!("Error parsing classad: {msg}")))
+}
+
+impl<'a> Parser<'a> {
+    fn new(data: &'a str) -> Self {
+        Parser {
+            chars: data.chars().peekable(),
+        }
+    }
+
+    fn skip_ws(&mut self) {
+        while let Some(c) = self.chars.peek() {
+            if c.is_whitespace() {
+                self.chars.next();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn expect(&mut self, want: char) -> Result<(), Box<dyn Error>> {
+        self.skip_ws();
+        match self.chars.next() {
+            Some(c) if c == want => Ok(()),
+            Some(c) => Err(parse_err(&format!("expected '{want}', found '{c}'"))),
+            None => Err(parse_err(&format!("expected '{want}', found end of input"))),
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, Box<dyn Error>> {
+        self.skip_ws();
+        let mut ret = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if c.is_alphanumeric() || c == '_' || c == '.' {
+                ret.push(c);
+                self.chars.next();
+            } else {
+                break;
+            }
+        }
+        if ret.is_empty() {
+            return Err(parse_err("expected attribute name"));
+        }
+        Ok(ret)
+    }
+
+    fn string(&mut self) -> Result<String, Box<dyn Error>> {
+        self.expect('"')?;
+        let mut ret = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(ret),
+                Some('\\') => match self.chars.next() {
+                    Some('n') => ret.push('\n'),
+                    Some('t') => ret.push('\t'),
+                    Some(c) => ret.push(c),
+                    None => return Err(parse_err("unterminated string")),
+                },
+                Some(c) => ret.push(c),
+                None => return Err(parse_err("unterminated string")),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, Box<dyn Error>> {
+        self.skip_ws();
+        match self.chars.peek() {
+            Some('"') => Ok(Value::Str(self.string()?)),
+            Some('[') => Ok(Value::Ad(self.ad()?)),
+            Some('{') => {
+                self.expect('{')?;
+                let mut items = Vec::new();
+                self.skip_ws();
+                if self.chars.peek() == Some(&'}') {
+                    self.chars.next();
+                    return Ok(Value::List(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_ws();
+                    match self.chars.next() {
+                        Some(',') => continue,
+                        Some('}') => return Ok(Value::List(items)),
+                        _ => return Err(parse_err("unterminated list")),
+                    }
+                }
+            }
+            Some(_) => {
+                let mut word = String::new();
+                while let Some(&c) = self.chars.peek() {
+                    if c.is_alphanumeric() || c == '.' || c == '-' || c == '+' || c == '_' {
+                        word.push(c);
+                        self.chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                if word.eq_ignore_ascii_case("true") {
+                    Ok(Value::Bool(true))
+                } else if word.eq_ignore_ascii_case("false") {
+                    Ok(Value::Bool(false))
+                } else if word.eq_ignore_ascii_case("undefined") {
+                    Ok(Value::Undefined)
+                } else if let Ok(n) = word.parse::<i64>() {
+                    Ok(Value::Int(n))
+                } else if let Ok(n) = word.parse::<f64>() {
+                    Ok(Value::Real(n))
+                } else {
+                    Err(parse_err(&format!("unsupported value '{word}'")))
+                }
+            }
+            None => Err(parse_err("expected value, found end of input")),
+        }
+    }
+
+    fn ad(&mut self) -> Result<ClassAd, Box<dyn Error>> {
+        self.expect('[')?;
+        let mut ret = ClassAd::new();
+        loop {
+            self.skip_ws();
+            match self.chars.peek() {
+                Some(']') => {
+                    self.chars.next();
+                    return Ok(ret);
+                }
+                Some(';') => {
+                    self.chars.next();
+                }
+                Some(_) => {
+                    let key = self.ident()?;
+                    self.expect('=')?;
+                    let value = self.value()?;
+                    ret.insert(&key, value);
+                }
+                None => return Err(parse_err("unterminated classad")),
+            }
+        }
+    }
+}
+
+/// Parse a sequence of ClassAds.
+///
+/// Accepts either the new bracketed format (`[ A = 1; B = "x" ]`) or the old
+/// line-oriented format, where ads are separated by blank lines.
+pub fn parse_ads(data: &str) -> Result<Vec<ClassAd>, Box<dyn Error>> {
+    let mut ret = Vec::new();
+    if data.trim_start().starts_with('[') {
+        let mut parser = Parser::new(data);
+        loop {
+            parser.skip_ws();
+            if parser.chars.peek().is_none() {
+                break;
+            }
+            ret.push(parser.ad()?);
+        }
+    } else {
+        let mut ad = ClassAd::new();
+        for line in data.lines() {
+            let line = line.trim();
+            if line.is_empty() {
+                if ad != ClassAd::new() {
+                    ret.push(std::mem::take(&mut ad));
+                }
+                continue;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some(parts) => parts,
+                None => return Err(parse_err(&format!("invalid line '{line}'"))),
+            };
+            let value = Parser::new(value).value()?;
+            ad.insert(key.trim(), value);
+        }
+        if ad != ClassAd::new() {
+            ret.push(ad);
+        }
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_new_format() {
+        let data = r#"[ LocalFileName = "/tmp/a b"; Url = "osdf:///ns/a" ]
+[ localfilename = "/tmp/\"c\""; Url = "osdf:///ns/c"; Size = 12; Ok = true ]
+"#;
+        let ads = parse_ads(data).unwrap();
+        assert_eq!(ads.len(), 2);
+        assert_eq!(ads[0].get_str("Url"), Some("osdf:///ns/a"));
+        assert_eq!(ads[0].get_str("LocalFileName"), Some("/tmp/a b"));
+        assert_eq!(ads[1].get_str("LocalFileName"), Some("/tmp/\"c\""));
+        assert_eq!(ads[1].get("size"), Some(&Value::Int(12)));
+        assert_eq!(ads[1].get("Ok"), Some(&Value::Bool(true)));
+    }
+
+    #[test]
+    fn test_parse_old_format() {
+        let data = "Url = \"osdf:///ns/a\"\nLocalFileName = \"/tmp/a\"\n\nUrl = \"osdf:///ns/b\"\nLocalFileName = \"/tmp/b\"\n";
+        let ads = parse_ads(data).unwrap();
+        assert_eq!(ads.len(), 2);
+        assert_eq!(ads[1].get_str("Url"), Some("osdf:///ns/b"));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut inner = ClassAd::new();
+        inner.insert("ErrorType", Value::Str("Transfer".into()));
+        inner.insert("ErrorCode", Value::Int(500));
+        let mut ad = ClassAd::new();
+        ad.insert("TransferSuccess", Value::Bool(false));
+        ad.insert("TransferError", Value::Str("it \"broke\"\n".into()));
+        ad.insert("Seconds", Value::Real(1.5));
+        ad.insert("TransferErrorData", Value::List(vec![Value::Ad(inner)]));
+
+        let ads = parse_ads(&ad.to_string()).unwrap();
+        assert_eq!(ads, vec![ad]);
+    }
+
+    #[test]
+    fn test_parse_error() {
+        assert!(parse_ads("[ Url = \"unterminated ]").is_err());
+        assert!(parse_ads("not a classad").is_err());
+    }
+}
@@ -0,0 +1,437 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::error::MyError;
+use crate::pelican::OSDF_DIRECTOR;
+
+pub const SYSTEM_CONFIG: &str = "/etc/dumb-pelican-client/config.toml";
+const USER_CONFIG: &str = "dumb-pelican-client/config.toml";
+const USER_TOKENS: &str = "dumb-pelican-client/tokens";
+
+const DEFAULT_TIMEOUT: u64 = 3600;
+const DEFAULT_CONNECT_TIMEOUT: u64 = 60;
+const DEFAULT_RETRIES: u32 = 4;
+
+/// Settings that can be changed for urls under a prefix.
+#[derive(serde::Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct PrefixFile {
+    director: Option<String>,
+    timeout: Option<u64>,
+    retries: Option<u32>,
+}
+
+#[derive(serde::Deserialize, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    osdf_director: Option<String>,
+    timeout: Option<u64>,
+    connect_timeout: Option<u64>,
+    retries: Option<u32>,
+    cred_dir: Option<String>,
+    issuer: Option<String>,
+    client_secret_file: Option<String>,
+    write_back_tokens: Option<bool>,
+    #[serde(default)]
+    namespace: BTreeMap<String, PrefixFile>,
+}
+
+/// Values given on the command line, which beat every config file.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CliOverrides {
+    pub director: Option<String>,
+    pub timeout: Option<u64>,
+    pub connect_timeout: Option<u64>,
+    pub retries: Option<u32>,
+}
+
+/// A value and where it came from.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct Sourced<T> {
+    pub value: T,
+    pub source: String,
+}
+
+impl<T> Sourced<T> {
+    fn new(value: T, source: &str) -> Self {
+        Sourced {
+            value,
+            source: source.to_string(),
+        }
+    }
+
+    fn set(&mut self, value: Option<T>, source: &str) {
+        if let Some(value) = value {
+            *self = Self::new(value, source);
+        }
+    }
+}
+
+fn set_opt<T>(slot: &mut Option<Sourced<T>>, value: Option<T>, source: &str) {
+    if let Some(value) = value {
+        *slot = Some(Sourced::new(value, source));
+    }
+}
+
+/// Overrides for urls starting with a prefix, like `osdf:///icecube`.
+#[derive(serde::Serialize, Debug, Default, Clone, PartialEq)]
+pub struct PrefixOverrides {
+    pub director: Option<Sourced<String>>,
+    pub timeout: Option<Sourced<u64>>,
+    pub retries: Option<Sourced<u32>>,
+}
+
+/// The merged configuration.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct Config {
+    pub osdf_director: Sourced<String>,
+    /// Seconds for a whole request
+    pub timeout: Sourced<u64>,
+    pub connect_timeout: Sourced<u64>,
+    /// Retries after the first attempt
+    pub retries: Sourced<u32>,
+    pub cred_dir: Option<Sourced<String>>,
+    /// The token issuer for credentials that don't name one
+    pub issuer: Option<Sourced<String>>,
+    /// A file with the secret of a confidential OAuth2 client
+    pub client_secret_file: Option<Sourced<String>>,
+    /// Save refreshed tokens back to their credential files
+    pub write_back_tokens: Sourced<bool>,
+    pub namespace: BTreeMap<String, PrefixOverrides>,
+    #[serde(skip)]
+    cli: CliOverrides,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            osdf_director: Sourced::new(OSDF_DIRECTOR.to_string(), "default"),
+            timeout: Sourced::new(DEFAULT_TIMEOUT, "default"),
+            connect_timeout: Sourced::new(DEFAULT_CONNECT_TIMEOUT, "default"),
+            retries: Sourced::new(DEFAULT_RETRIES, "default"),
+            cred_dir: None,
+            issuer: None,
+            client_secret_file: None,
+            write_back_tokens: Sourced::new(false, "default"),
+            namespace: BTreeMap::new(),
+            cli: CliOverrides::default(),
+        }
+    }
+}
+
+/// The settings that apply to one url.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    /// The director to ask, if not the usual one for the url scheme
+    pub director: Option<String>,
+    pub timeout: Duration,
+    pub retries: u32,
+}
+
+/// The user config file, under `$XDG_CONFIG_HOME` or `~/.config`.
+fn user_config_path() -> Option<PathBuf> {
+    match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join(USER_CONFIG)),
+        _ => env::var_os("HOME").map(|home| PathBuf::from(home).join(".config").join(USER_CONFIG)),
+    }
+}
+
+/// Where `token get` saves credentials, under `$XDG_DATA_HOME` or
+/// `~/.local/share`.
+pub fn user_token_dir() -> Option<PathBuf> {
+    match env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join(USER_TOKENS)),
+        _ => env::var_os("HOME").map(|home| {
+            PathBuf::from(home)
+                .join(".local")
+                .join("share")
+                .join(USER_TOKENS)
+        }),
+    }
+}
+
+/// The config files to read, lowest precedence first.
+pub fn config_paths(extra: Option<&str>) -> Vec<PathBuf> {
+    let mut ret = vec![PathBuf::from(SYSTEM_CONFIG)];
+    ret.extend(user_config_path());
+    ret.extend(extra.map(PathBuf::from));
+    ret
+}
+
+impl Config {
+    /// Merge the files that exist, then `_CONDOR_CREDS`, then the command line.
+    pub fn load(paths: &[PathBuf], cli: &CliOverrides) -> Result<Self, Box<dyn Error>> {
+        let mut config = Config::default();
+        for path in paths.iter() {
+            let data = match std::fs::read_to_string(path) {
+                Ok(data) => data,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(Box::new(MyError::Config(format!(
+                        "cannot read {}: {}",
+                        path.display(),
+                        e
+                    ))));
+                }
+            };
+            log::info!("reading config {}", path.display());
+            let file: ConfigFile = toml::from_str(&data)
+                .map_err(|e| MyError::Config(format!("cannot parse {}: {}", path.display(), e)))?;
+            config.merge(file, &path.to_string_lossy());
+        }
+
+        if let Ok(dir) = env::var("_CONDOR_CREDS") {
+            config.cred_dir = Some(Sourced::new(dir, "env _CONDOR_CREDS"));
+        }
+
+        config
+            .osdf_director
+            .set(cli.director.clone(), "command line");
+        config.timeout.set(cli.timeout, "command line");
+        config
+            .connect_timeout
+            .set(cli.connect_timeout, "command line");
+        config.retries.set(cli.retries, "command line");
+        config.cli = cli.clone();
+        Ok(config)
+    }
+
+    fn merge(&mut self, file: ConfigFile, source: &str) {
+        self.osdf_director.set(file.osdf_director, source);
+        self.timeout.set(file.timeout, source);
+        self.connect_timeout.set(file.connect_timeout, source);
+        self.retries.set(file.retries, source);
+        set_opt(&mut self.cred_dir, file.cred_dir, source);
+        set_opt(&mut self.issuer, file.issuer, source);
+        set_opt(
+            &mut self.client_secret_file,
+            file.client_secret_file,
+            source,
+        );
+        self.write_back_tokens.set(file.write_back_tokens, source);
+        for (prefix, values) in file.namespace {
+            let entry = self.namespace.entry(prefix).or_default();
+            set_opt(&mut entry.director, values.director, source);
+            set_opt(&mut entry.timeout, values.timeout, source);
+            set_opt(&mut entry.retries, values.retries, source);
+        }
+    }
+
+    /// The settings for a url: the global values, then the overrides of each
+    /// matching prefix from shortest to longest, then the command line.
+    pub fn settings_for(&self, url: &str) -> Settings {
+        let mut ret = Settings {
+            director: None,
+            timeout: Duration::from_secs(self.timeout.value),
+            retries: self.retries.value,
+        };
+        if url.starts_with("osdf://") {
+            ret.director = Some(self.osdf_director.value.clone());
+        }
+
+        let mut matching: Vec<_> = self
+            .namespace
+            .iter()
+            .filter(|(prefix, _)| prefix_matches(prefix, url))
+            .collect();
+        matching.sort_by_key(|(prefix, _)| prefix.trim_end_matches('/').len());
+        for (_, overrides) in matching {
+            if let Some(v) = &overrides.director {
+                ret.director = Some(v.value.clone());
+            }
+            if let Some(v) = &overrides.timeout {
+                ret.timeout = Duration::from_secs(v.value);
+            }
+            if let Some(v) = &overrides.retries {
+                ret.retries = v.value;
+            }
+        }
+
+        if let (Some(director), true) = (&self.cli.director, url.starts_with("osdf://")) {
+            ret.director = Some(director.clone());
+        }
+        if let Some(timeout) = self.cli.timeout {
+            ret.timeout = Duration::from_secs(timeout);
+        }
+        if let Some(retries) = self.cli.retries {
+            ret.retries = retries;
+        }
+        ret
+    }
+
+    /// Print each value with where it came from.
+    pub fn show(&self) {
+        let line = |key: &str, value: String, source: &str| {
+            println!("{:<40} # {}", format!("{} = {}", key, value), source);
+        };
+        let quote = |s: &str| format!("{:?}", s);
+        line(
+            "osdf_director",
+            quote(&self.osdf_director.value),
+            &self.osdf_director.source,
+        );
+        line(
+            "timeout",
+            self.timeout.value.to_string(),
+            &self.timeout.source,
+        );
+        line(
+            "connect_timeout",
+            self.connect_timeout.value.to_string(),
+            &self.connect_timeout.source,
+        );
+        line(
+            "retries",
+            self.retries.value.to_string(),
+            &self.retries.source,
+        );
+        match &self.cred_dir {
+            Some(v) => line("cred_dir", quote(&v.value), &v.source),
+            None => println!("# cred_dir is not set"),
+        }
+        match &self.issuer {
+            Some(v) => line("issuer", quote(&v.value), &v.source),
+            None => println!("# issuer is not set"),
+        }
+        match &self.client_secret_file {
+            Some(v) => line("client_secret_file", quote(&v.value), &v.source),
+            None => println!("# client_secret_file is not set"),
+        }
+        line(
+            "write_back_tokens",
+            self.write_back_tokens.value.to_string(),
+            &self.write_back_tokens.source,
+        );
+        for (prefix, overrides) in self.namespace.iter() {
+            println!();
+            println!("[namespace.{:?}]", prefix);
+            if let Some(v) = &overrides.director {
+                line("director", quote(&v.value), &v.source);
+            }
+            if let Some(v) = &overrides.timeout {
+                line("timeout", v.value.to_string(), &v.source);
+            }
+            if let Some(v) = &overrides.retries {
+                line("retries", v.value.to_string(), &v.source);
+            }
+        }
+    }
+}
+
+/// Whether `url` is `prefix` or under it, on a path component boundary.
+fn prefix_matches(prefix: &str, url: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    match url.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Set the configuration for the rest of the process.
+pub fn init(config: Config) {
+    if CONFIG.set(config).is_err() {
+        log::warn!("configuration was already set");
+    }
+}
+
+/// The configuration from `init`, or the defaults if it was never called.
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_load_and_settings_for() {
+        let tmp_dir = TempDir::new().unwrap();
+        let system = tmp_dir.path().join("system.toml");
+        let user = tmp_dir.path().join("user.toml");
+        std::fs::write(
+            &system,
+            r#"
+timeout = 100
+retries = 2
+cred_dir = "/creds"
+
+[namespace."osdf:///icecube"]
+timeout = 200
+"#,
+        )
+        .unwrap();
+        std::fs::write(
+            &user,
+            r#"
+connect_timeout = 5
+write_back_tokens = true
+client_secret_file = "/secrets/client"
+
+[namespace."osdf:///icecube/wipac"]
+retries = 7
+director = "https://director.example/api/v1.0/director/origin"
+"#,
+        )
+        .unwrap();
+        let paths = vec![system.clone(), user.clone(), tmp_dir.path().join("missing")];
+
+        let config = temp_env::with_var_unset("_CONDOR_CREDS", || {
+            Config::load(&paths, &CliOverrides::default()).unwrap()
+        });
+        assert_eq!(config.timeout.value, 100);
+        assert_eq!(config.timeout.source, system.to_string_lossy());
+        assert_eq!(config.connect_timeout.value, 5);
+        assert_eq!(config.connect_timeout.source, user.to_string_lossy());
+        assert_eq!(config.osdf_director.source, "default");
+        assert!(config.write_back_tokens.value);
+        assert_eq!(config.cred_dir.as_ref().unwrap().value, "/creds");
+        assert_eq!(
+            config.client_secret_file.as_ref().unwrap().value,
+            "/secrets/client"
+        );
+
+        let settings = config.settings_for("osdf:///icecube/wipac/file");
+        assert_eq!(settings.timeout, Duration::from_secs(200));
+        assert_eq!(settings.retries, 7);
+        assert_eq!(
+            settings.director.as_deref(),
+            Some("https://director.example/api/v1.0/director/origin")
+        );
+
+        // not on a component boundary
+        let settings = config.settings_for("osdf:///icecube-other/file");
+        assert_eq!(settings.timeout, Duration::from_secs(100));
+        assert_eq!(settings.director.as_deref(), Some(OSDF_DIRECTOR));
+
+        // the command line wins, and _CONDOR_CREDS beats the files
+        let cli = CliOverrides {
+            retries: Some(0),
+            ..Default::default()
+        };
+        let config = temp_env::with_var("_CONDOR_CREDS", Some("/job/creds"), || {
+            Config::load(&paths, &cli).unwrap()
+        });
+        assert_eq!(config.settings_for("osdf:///icecube/wipac/file").retries, 0);
+        assert_eq!(config.retries.source, "command line");
+        assert_eq!(config.cred_dir.unwrap().source, "env _CONDOR_CREDS");
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let tmp_dir = TempDir::new().unwrap();
+        let paths = vec![tmp_dir.path().join("bad.toml")];
+        std::fs::write(&paths[0], "timeout = \"soon\"\n").unwrap();
+        assert!(Config::load(&paths, &CliOverrides::default()).is_err());
+
+        std::fs::write(&paths[0], "unknown_key = 1\n").unwrap();
+        assert!(Config::load(&paths, &CliOverrides::default()).is_err());
+    }
+}
@@ -1,25 +1,36 @@
 use std::env;
 use std::error::Error;
 use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::OnceLock;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::config;
 use crate::error::MyError;
+use crate::oauth;
 use crate::pelican::PelicanInfo;
-use crate::transfer::Transfer;
+use crate::token::decode_jwt_claims;
+use crate::transfer::{Transfer, Verb};
 
-fn get_cred_dir() -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    let dir_path = match env::var("_CONDOR_CREDS") {
-        Ok(val) => val,
-        Err(_) => {
-            return Err(Box::new(MyError::Credentials(
+/// `_CONDOR_CREDS`, or else `cred_dir` from the config.
+pub(crate) fn get_cred_dir() -> Result<String, Box<dyn std::error::Error>> {
+    match env::var("_CONDOR_CREDS") {
+        Ok(val) => Ok(val),
+        Err(_) => match &config::get().cred_dir {
+            Some(dir) => Ok(dir.value.clone()),
+            None => Err(Box::new(MyError::Credentials(
                 "_CONDOR_CREDS env variable not set".into(),
-            )));
-        }
-    };
+            ))),
+        },
+    }
+}
+
+pub(crate) fn get_cred_files(dir_path: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
     log::info!("Reading cred directory: {}", dir_path);
 
     let mut ret = Vec::new();
-    match fs::read_dir(&dir_path) {
+    match fs::read_dir(dir_path) {
         Ok(entries) => {
             for entry in entries {
                 match entry {
@@ -46,33 +57,326 @@ fn get_cred_dir() -> Result<Vec<String>, Box<dyn std::error::Error>> {
     Ok(ret)
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Clone)]
+/// A list that may also be given as one space-separated string, like the
+/// `scope` of an OAuth2 token response or the `aud` claim of a JWT.
+fn string_or_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        String(String),
+        List(Vec<String>),
+    }
+    Ok(
+        match <Option<StringOrList> as serde::Deserialize>::deserialize(deserializer)? {
+            Some(StringOrList::String(s)) => s.split_whitespace().map(String::from).collect(),
+            Some(StringOrList::List(list)) => list,
+            None => Vec::new(),
+        },
+    )
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Clone, Default)]
 pub(crate) struct Credential {
     pub access_token: String,
+    #[serde(default = "default_token_type")]
     pub token_type: String,
+    #[serde(default)]
     pub expires_in: i32,
+    #[serde(default)]
     pub expires_at: f32,
+    #[serde(default, deserialize_with = "string_or_list")]
     pub scope: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    /// The token issuer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    #[serde(
+        default,
+        deserialize_with = "string_or_list",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub aud: Vec<String>,
+    /// The OAuth2 client to refresh the token as
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    /// The file this was read from
+    #[serde(skip)]
+    pub source: Option<String>,
+}
+
+fn default_token_type() -> String {
+    "bearer".into()
+}
+
+/// `expires_at` for tokens that don't say when they expire.
+pub(crate) const NO_EXPIRY: f32 = f32::MAX;
+
+impl Credential {
+    /// Parse a credential file, which may be HTCondor's format or a standard
+    /// OAuth2 token response. Whatever it lacks is taken from the JWT claims
+    /// of the access token, if it is a JWT.
+    pub fn parse(json: &str) -> Result<Self, Box<dyn Error>> {
+        Self::parse_issued(json, SystemTime::now())
+    }
+
+    /// Read a credential file, where a relative `expires_in` counts from
+    /// when the file was written.
+    pub fn read_file(path: &str) -> Result<Self, Box<dyn Error>> {
+        let issued = fs::metadata(path)?.modified()?;
+        let mut cred = Self::parse_issued(&fs::read_to_string(path)?, issued)?;
+        cred.source = Some(path.to_string());
+        Ok(cred)
+    }
+
+    fn parse_issued(json: &str, issued: SystemTime) -> Result<Self, Box<dyn Error>> {
+        let mut cred: Credential = serde_json::from_str(json)?;
+        match decode_jwt_claims(&cred.access_token) {
+            Ok(claims) => cred.fill_from_claims(&claims),
+            Err(e) => log::debug!("access token claims are unavailable: {}", e),
+        }
+        if cred.expires_at <= 0. {
+            cred.expires_at = if cred.expires_in > 0 {
+                let issued = issued.duration_since(UNIX_EPOCH)?.as_secs_f32();
+                issued + cred.expires_in as f32
+            } else {
+                NO_EXPIRY
+            };
+        }
+        Ok(cred)
+    }
+
+    /// Make a credential from a raw JWT, taking the scope and expiry from its claims.
+    pub fn from_jwt(token: &str, source: &str) -> Result<Self, Box<dyn Error>> {
+        let claims = decode_jwt_claims(token)?;
+        let mut cred = Credential {
+            access_token: token.to_string(),
+            token_type: default_token_type(),
+            source: Some(source.to_string()),
+            ..Default::default()
+        };
+        cred.fill_from_claims(&claims);
+        if cred.expires_at <= 0. {
+            cred.expires_at = NO_EXPIRY;
+        }
+        Ok(cred)
+    }
+
+    /// Fill in the scope, expiry, issuer, and audience, where missing.
+    fn fill_from_claims(&mut self, claims: &serde_json::Map<String, serde_json::Value>) {
+        let strings = |claim: &str| -> Vec<String> {
+            match claims.get(claim) {
+                Some(serde_json::Value::String(s)) => {
+                    s.split_whitespace().map(String::from).collect()
+                }
+                Some(serde_json::Value::Array(a)) => a
+                    .iter()
+                    .filter_map(|v| v.as_str().map(String::from))
+                    .collect(),
+                _ => Vec::new(),
+            }
+        };
+        if self.scope.is_empty() {
+            self.scope = strings("scope");
+        }
+        if self.aud.is_empty() {
+            self.aud = strings("aud");
+        }
+        if self.iss.is_none() {
+            self.iss = claims.get("iss").and_then(|i| i.as_str()).map(String::from);
+        }
+        if self.expires_at <= 0.
+            && let Some(exp) = claims.get("exp").and_then(|e| e.as_f64())
+        {
+            self.expires_at = exp as f32;
+            if self.expires_in <= 0 {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs_f64())
+                    .unwrap_or_default();
+                self.expires_in = (exp - now).max(0.) as i32;
+            }
+        }
+    }
+}
+
+/// Read a token file, ignoring surrounding whitespace. Missing or empty
+/// files give None.
+fn read_token_file(path: &str) -> Option<String> {
+    let token = fs::read_to_string(path).ok()?.trim().to_string();
+    if token.is_empty() { None } else { Some(token) }
+}
+
+/// Find a bearer token the way the WLCG token discovery spec says to:
+/// `BEARER_TOKEN`, then `BEARER_TOKEN_FILE`, then `$XDG_RUNTIME_DIR/bt_u<uid>`,
+/// then `/tmp/bt_u<uid>`. Returns where it was found, and the token.
+pub(crate) fn discover_bearer_token(uid: Option<u32>) -> Option<(String, String)> {
+    if let Ok(token) = env::var("BEARER_TOKEN") {
+        let token = token.trim();
+        if !token.is_empty() {
+            return Some(("env BEARER_TOKEN".into(), token.to_string()));
+        }
+    }
+    let mut paths = Vec::new();
+    if let Ok(path) = env::var("BEARER_TOKEN_FILE") {
+        paths.push(path);
+    }
+    if let Some(uid) = uid {
+        if let Ok(dir) = env::var("XDG_RUNTIME_DIR") {
+            paths.push(format!("{}/bt_u{}", dir, uid));
+        }
+        paths.push(format!("/tmp/bt_u{}", uid));
+    }
+    paths
+        .into_iter()
+        .find_map(|path| read_token_file(&path).map(|token| (path, token)))
 }
 
-pub struct Credentials(Vec<Credential>);
+/// Our uid, from the owner of `/proc/self`, or None where there is no `/proc`.
+pub(crate) fn current_uid() -> Option<u32> {
+    fs::metadata("/proc/self").map(|m| m.uid()).ok()
+}
+
+/// Read the `*.use` credential files in a directory, skipping the ones
+/// that can't be read. Also returns why each skipped file was skipped.
+fn read_cred_dir(dir_path: &str) -> Result<(Vec<Credential>, Vec<String>), Box<dyn Error>> {
+    let mut creds = Vec::new();
+    let mut skipped = Vec::new();
+    for filename in get_cred_files(dir_path)? {
+        log::info!("reading cred {}", filename);
+        // one bad file shouldn't keep the others from being used
+        match Credential::read_file(&filename) {
+            Ok(cred) => {
+                log::info!("found scope {:?}", cred.scope);
+                creds.push(cred);
+            }
+            Err(e) => {
+                log::warn!("skipping cred {}: {}", filename, e);
+                skipped.push(format!("{}: {}", filename, e));
+            }
+        }
+    }
+    Ok((creds, skipped))
+}
+
+pub struct Credentials {
+    creds: Vec<Credential>,
+    /// Use credentials for any path, whatever their scopes say
+    skip_scope_check: bool,
+    /// Each credential after refreshing it, or None if that failed
+    refreshed: Vec<OnceLock<Option<Credential>>>,
+}
 
 impl Credentials {
-    #[allow(dead_code)]
     pub fn new(data: Vec<Credential>) -> Self {
-        Credentials(data)
+        Credentials {
+            refreshed: data.iter().map(|_| OnceLock::new()).collect(),
+            creds: data,
+            skip_scope_check: false,
+        }
     }
 
-    pub fn from_condor() -> Result<Self, Box<dyn std::error::Error>> {
-        let mut ret = Vec::new();
-        for filename in get_cred_dir()? {
-            log::info!("reading cred {}", filename);
-            let json = fs::read_to_string(&filename)?;
-            let data: Credential = serde_json::from_str(&json)?;
-            log::info!("found scope {:?}", data.scope);
-            ret.push(data);
+    /// Use only this token. Its scope and expiry come from its JWT claims,
+    /// so a token that is not a JWT needs `skip_scope_check`.
+    pub fn from_token(
+        token: &str,
+        source: &str,
+        skip_scope_check: bool,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let cred = match Credential::from_jwt(token, source) {
+            Ok(cred) => cred,
+            Err(e) if !skip_scope_check => {
+                return Err(Box::new(MyError::Credentials(format!(
+                    "cannot read the scope of the token from {}: {}; use --skip-scope-check to use it anyway",
+                    source, e
+                ))));
+            }
+            Err(_) => Credential {
+                access_token: token.to_string(),
+                token_type: default_token_type(),
+                // the expiry is unknown, so never treat it as expired
+                expires_at: NO_EXPIRY,
+                source: Some(source.to_string()),
+                ..Default::default()
+            },
+        };
+        let mut ret = Self::new(vec![cred]);
+        ret.skip_scope_check = skip_scope_check;
+        Ok(ret)
+    }
+
+    /// Use only the token in this file, like `from_token`.
+    pub fn from_token_file(
+        path: &str,
+        skip_scope_check: bool,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let token = fs::read_to_string(path)?;
+        if token.trim().is_empty() {
+            return Err(Box::new(MyError::Credentials(format!(
+                "token file {} is empty",
+                path
+            ))));
+        }
+        Self::from_token(token.trim(), path, skip_scope_check)
+    }
+
+    /// Pick credentials without checking their scopes against the path.
+    pub fn set_skip_scope_check(&mut self, skip: bool) {
+        self.skip_scope_check = skip;
+    }
+
+    /// HTCondor credentials, if there are any, followed by those saved by
+    /// `token get`, then a bearer token found by WLCG token discovery.
+    pub fn discover() -> Result<Self, Box<dyn std::error::Error>> {
+        let mut skipped = Vec::new();
+        let (mut ret, condor) = match get_cred_dir() {
+            Ok(dir) => {
+                let (creds, bad) = read_cred_dir(&dir)?;
+                skipped.extend(bad);
+                (creds, format!("there are no usable *.use files in {}", dir))
+            }
+            Err(e) => {
+                log::info!("no HTCondor credentials: {}", e);
+                (Vec::new(), "_CONDOR_CREDS is not set".to_string())
+            }
+        };
+        if let Some(dir) = config::user_token_dir()
+            && dir.is_dir()
+        {
+            let (creds, bad) = read_cred_dir(&dir.to_string_lossy())?;
+            ret.extend(creds);
+            skipped.extend(bad);
+        }
+        if let Some((source, token)) = discover_bearer_token(current_uid()) {
+            log::info!("found bearer token in {}", source);
+            match Credential::from_jwt(&token, &source) {
+                Ok(cred) => ret.push(cred),
+                Err(e) => log::warn!("ignoring bearer token from {}: {}", source, e),
+            }
         }
-        Ok(Self(ret))
+        if ret.is_empty() {
+            let mut msg = format!(
+                "no credentials found: {}, no tokens were saved by `token get`, and there is no bearer token",
+                condor
+            );
+            if !skipped.is_empty() {
+                msg = format!("{}; skipped {}", msg, skipped.join("; "));
+            }
+            return Err(Box::new(MyError::Credentials(msg)));
+        }
+        Ok(Self::new(ret))
+    }
+
+    /// Read the `*.use` credential files in a directory.
+    pub fn from_dir(dir_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self::new(read_cred_dir(dir_path)?.0))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Credential> {
+        self.creds.iter()
     }
 
     pub fn get_correct_cred(
@@ -80,8 +384,196 @@ impl Credentials {
         transfer: &Transfer,
         info: &PelicanInfo,
     ) -> Result<&Credential, Box<dyn Error>> {
-        let prefix = info.get_osdf_prefix();
-        let path = match transfer.url.split_once(prefix) {
+        self.get_cred_for_url(&transfer.url, scope_options(transfer.mode), info)
+    }
+
+    /// Like `get_correct_cred`, but says why each credential was picked or not.
+    pub fn explain_correct_cred(
+        &self,
+        transfer: &Transfer,
+        info: &PelicanInfo,
+    ) -> Result<CredChoice, Box<dyn Error>> {
+        self.choose_cred(&[(&transfer.url, scope_options(transfer.mode))], info)
+    }
+
+    /// Find a credential with one of the `scope_options` that covers the url.
+    pub fn get_cred_for_url(
+        &self,
+        url: &str,
+        scope_options: &[&str],
+        info: &PelicanInfo,
+    ) -> Result<&Credential, Box<dyn Error>> {
+        self.get_cred_for_urls(&[(url, scope_options)], info)
+    }
+
+    /// Find a single credential that covers every (url, scope options) pair.
+    pub fn get_cred_for_urls(
+        &self,
+        requirements: &[(&str, &[&str])],
+        info: &PelicanInfo,
+    ) -> Result<&Credential, Box<dyn Error>> {
+        let choice = self.choose_cred(requirements, info)?;
+        match choice.selected {
+            Some(i) => {
+                if choice.expired {
+                    if let Some(cred) = self.refreshed(i) {
+                        // the issuer may have narrowed the scopes
+                        let paths = object_paths(requirements, info)?;
+                        match paths.iter().find(|(path, scope_options)| {
+                            cred_covers(cred, scope_options, path).is_none()
+                        }) {
+                            Some((path, _)) if !self.skip_scope_check => {
+                                log::warn!("refreshed credential no longer covers {}", path);
+                            }
+                            _ => return Ok(cred),
+                        }
+                    }
+                    log::warn!("only valid cred is expired. will try using it anyway");
+                }
+                Ok(&self.creds[i])
+            }
+            None => Err(Box::new(MyError::Credentials(
+                "No matching credentials for url".into(),
+            ))),
+        }
+    }
+
+    /// Refresh an expired credential with its refresh token, once, and
+    /// write it back to its file if the config says to.
+    fn refreshed(&self, i: usize) -> Option<&Credential> {
+        let cred = &self.creds[i];
+        cred.refresh_token.as_ref()?;
+        self.refreshed[i]
+            .get_or_init(|| {
+                let secret = oauth::client_secret().unwrap_or_else(|e| {
+                    log::warn!("cannot read the client secret: {}", e);
+                    None
+                });
+                let oauth_client = cred.client_id.as_deref().map(|id| oauth::OAuthClient {
+                    id,
+                    secret: secret.as_deref(),
+                });
+                let new = match oauth::refresh(&oauth::http_client(), cred, oauth_client.as_ref()) {
+                    Ok(new) => new,
+                    Err(e) => {
+                        log::warn!("cannot refresh expired credential: {}", e);
+                        return None;
+                    }
+                };
+                log::info!("refreshed expired credential");
+                if let Some(path) = &cred.source
+                    && Path::new(path).is_file()
+                {
+                    if !config::get().write_back_tokens.value {
+                        log::info!(
+                            "not saving the refreshed credential to {}: write_back_tokens is off",
+                            path
+                        );
+                    } else if let Err(e) = oauth::write_atomically(path, &new) {
+                        log::warn!("cannot write refreshed credential to {}: {}", path, e);
+                    }
+                }
+                Some(new)
+            })
+            .as_ref()
+    }
+
+    /// Pick the first unexpired credential covering every requirement,
+    /// falling back to an expired one, and note why the others lost.
+    fn choose_cred(
+        &self,
+        requirements: &[(&str, &[&str])],
+        info: &PelicanInfo,
+    ) -> Result<CredChoice, Box<dyn Error>> {
+        let paths = object_paths(requirements, info)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f32();
+        // how well each credential covers every path, or why it doesn't
+        let mut ranks = Vec::new();
+        let mut reasons = Vec::new();
+        for cred in self.creds.iter() {
+            let mut specificity = usize::MAX;
+            let mut missing = None;
+            for (path, scope_options) in paths.iter() {
+                match cred_covers(cred, scope_options, path) {
+                    _ if self.skip_scope_check => specificity = 0,
+                    Some(n) => specificity = specificity.min(n),
+                    None => {
+                        missing = Some((path, scope_options));
+                        break;
+                    }
+                }
+            }
+            match missing {
+                Some((path, scope_options)) => {
+                    ranks.push(None);
+                    reasons.push(format!(
+                        "rejected: no {} scope covering {}",
+                        scope_options.join(" or "),
+                        path
+                    ));
+                }
+                None => {
+                    ranks.push(Some((specificity, cred.expires_at)));
+                    reasons.push(String::new());
+                }
+            }
+        }
+
+        // the longest scope path wins, then the most time left
+        let best = |expired: bool| {
+            ranks
+                .iter()
+                .enumerate()
+                .filter_map(|(i, rank)| rank.map(|r| (i, r)))
+                .filter(|(_, (_, expires_at))| (*expires_at <= now) == expired)
+                .max_by(|(i, a), (j, b)| {
+                    a.0.cmp(&b.0)
+                        .then(a.1.total_cmp(&b.1))
+                        // prefer the earlier credential on a tie
+                        .then(j.cmp(i))
+                })
+        };
+        let (selected, expired) = match best(false) {
+            Some(winner) => (Some(winner), false),
+            None => (best(true), true),
+        };
+        for (i, rank) in ranks.iter().enumerate() {
+            let Some((specificity, expires_at)) = rank else {
+                continue;
+            };
+            reasons[i] = match selected {
+                Some((j, _)) if i == j && expired => "selected, even though it is expired".into(),
+                Some((j, _)) if i == j => "selected".into(),
+                _ if *expires_at <= now => "expired".into(),
+                Some((_, (best_specificity, _))) if *specificity < best_specificity => {
+                    "matches, but a more specific credential was picked".into()
+                }
+                _ => "matches, but a credential with more time left was picked".into(),
+            };
+        }
+        Ok(CredChoice {
+            selected: selected.map(|(i, _)| i),
+            expired: selected.is_some() && expired,
+            reasons,
+        })
+    }
+}
+
+/// A url or path, and the scopes that would allow the request.
+type Requirement<'a> = (&'a str, &'a [&'a str]);
+
+/// The object path of each url, with its scope options.
+fn object_paths<'a>(
+    requirements: &[Requirement<'a>],
+    info: &PelicanInfo,
+) -> Result<Vec<Requirement<'a>>, Box<dyn Error>> {
+    let prefix = info.get_osdf_prefix();
+    let mut paths = Vec::new();
+    for (url, scope_options) in requirements.iter() {
+        let path = match url.split_once(prefix) {
             Some(s) => s.1,
             None => {
                 return Err(Box::new(MyError::Credentials(
@@ -89,105 +581,385 @@ impl Credentials {
                 )));
             }
         };
-        let scope_options = match transfer.mode {
-            crate::transfer::Verb::Get => vec!["storage.read"],
-            crate::transfer::Verb::Put => vec!["storage.create", "storage.modify"],
-        };
         log::info!(
             "getting correct cred to match scope {:?} and path: {}",
             scope_options,
             path
         );
+        paths.push((path, *scope_options));
+    }
+    Ok(paths)
+}
 
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs_f32();
-        let mut expired_cred = None;
-        for cred in self.0.iter() {
-            for scope in cred.scope.iter() {
-                if let Some((pre, post)) = scope.split_once(':')
-                    && scope_options.contains(&pre)
-                    && path.starts_with(post)
-                {
-                    if cred.expires_at <= now {
-                        expired_cred = Some(cred);
-                    } else {
-                        return Ok(cred);
-                    }
-                }
-            }
-        }
+/// The scopes that allow a transfer in this mode.
+/// `storage.stage` is a superset of `storage.read`, and `storage.modify`
+/// of `storage.create`.
+fn scope_options(mode: Verb) -> &'static [&'static str] {
+    match mode {
+        Verb::Get => &["storage.read", "storage.stage"],
+        Verb::Put => &["storage.create", "storage.modify"],
+        Verb::Delete => &["storage.modify"],
+        Verb::Mkcol => &["storage.create", "storage.modify"],
+    }
+}
 
-        if let Some(cred) = expired_cred {
-            log::warn!("only valid cred is expired. will try using it anyway");
-            Ok(cred)
-        } else {
-            Err(Box::new(MyError::Credentials(
-                "No matching credentials for url".into(),
-            )))
-        }
+/// Which credential was picked for a request, and a reason for each credential.
+#[derive(Debug, PartialEq)]
+pub struct CredChoice {
+    pub selected: Option<usize>,
+    /// The selected credential is expired
+    pub expired: bool,
+    pub reasons: Vec<String>,
+}
+
+/// If `scope_path` covers `path` on `/` boundaries, how specific it is:
+/// the length of the scope path, so `/` covers everything with 0.
+fn scope_path_covers(scope_path: &str, path: &str) -> Option<usize> {
+    let scope_path = scope_path.trim_end_matches('/');
+    match path.strip_prefix(scope_path) {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => Some(scope_path.len()),
+        _ => None,
     }
 }
 
+/// If the credential has one of the `scope_options` for the path, the
+/// specificity of its most specific matching scope.
+fn cred_covers(cred: &Credential, scope_options: &[&str], path: &str) -> Option<usize> {
+    cred.scope
+        .iter()
+        .filter_map(|scope| {
+            // a scope without a path is for the whole namespace
+            let (pre, post) = scope.split_once(':').unwrap_or((scope, "/"));
+            if scope_options.contains(&pre) {
+                scope_path_covers(post, path)
+            } else {
+                None
+            }
+        })
+        .max()
+}
+
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
     use tempfile::{NamedTempFile, TempDir};
 
     use super::*;
-    use crate::{logging::test_logger, transfer::Verb};
+    use crate::logging::{make_jwt, test_cred, test_logger};
 
     #[test]
     fn test_credentials_from_condor() {
         test_logger();
 
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs_f32();
-        let test_cred = Credential {
-            access_token: "token".into(),
-            token_type: "bearer".into(),
-            expires_in: 3600,
-            expires_at: now + 3600.,
-            scope: vec![
-                "storage.read:/read/scope".into(),
-                "storage.modify:/write/scope".into(),
-            ],
-        };
+        let test_cred = test_cred(&["storage.read:/read/scope", "storage.modify:/write/scope"]);
 
         let tmp_dir = TempDir::new().unwrap();
         let file_path = tmp_dir.path().join("test_cred.use");
         let contents = serde_json::to_vec_pretty(&test_cred).unwrap();
-        fs::write(file_path, &contents).unwrap();
+        fs::write(&file_path, &contents).unwrap();
 
         temp_env::with_var("_CONDOR_CREDS", Some(tmp_dir.path().as_os_str()), || {
-            let creds = Credentials::from_condor().unwrap();
-            assert!(creds.0.len() == 1);
-            assert_eq!(*creds.0.first().unwrap(), test_cred);
+            let creds = Credentials::from_dir(&get_cred_dir().unwrap()).unwrap();
+            assert!(creds.creds.len() == 1);
+            let expected = Credential {
+                source: Some(file_path.to_str().unwrap().into()),
+                ..test_cred.clone()
+            };
+            assert_eq!(*creds.creds.first().unwrap(), expected);
         });
     }
 
     #[test]
-    fn test_get_correct_cred() {
+    fn test_credential_from_jwt() {
+        let token = make_jwt(r#"{"scope":"storage.read:/ storage.create:/out","exp":4000000000}"#);
+        let cred = Credential::from_jwt(&token, "env BEARER_TOKEN").unwrap();
+        assert_eq!(cred.scope, vec!["storage.read:/", "storage.create:/out"]);
+        assert_eq!(cred.expires_at, 4000000000.);
+        assert_eq!(cred.source.as_deref(), Some("env BEARER_TOKEN"));
+
+        assert!(Credential::from_jwt("opaque", "env BEARER_TOKEN").is_err());
+
+        // without an exp claim it never expires, rather than at 1970
+        let token = make_jwt(r#"{"scope":"storage.read:/"}"#);
+        let cred = Credential::from_jwt(&token, "env BEARER_TOKEN").unwrap();
+        assert_eq!(cred.expires_at, NO_EXPIRY);
+    }
+
+    #[test]
+    fn test_current_uid() {
+        // files we create are owned by us
+        let tmp_dir = TempDir::new().unwrap();
+        let uid = fs::metadata(tmp_dir.path()).unwrap().uid();
+        assert_eq!(current_uid(), Some(uid));
+    }
+
+    #[test]
+    fn test_discover_bearer_token() {
+        let tmp_dir = TempDir::new().unwrap();
+        let runtime_file = tmp_dir.path().join("bt_u1234");
+        let token_file = tmp_dir.path().join("token");
+        fs::write(&runtime_file, "runtime-token\n").unwrap();
+        fs::write(&token_file, " file-token \n").unwrap();
+        let runtime_dir = tmp_dir.path().to_str().unwrap();
+        let token_path = token_file.to_str().unwrap();
+
+        temp_env::with_vars(
+            [
+                ("BEARER_TOKEN", Some("env-token")),
+                ("BEARER_TOKEN_FILE", Some(token_path)),
+                ("XDG_RUNTIME_DIR", Some(runtime_dir)),
+            ],
+            || {
+                assert_eq!(
+                    discover_bearer_token(Some(1234)),
+                    Some(("env BEARER_TOKEN".into(), "env-token".into()))
+                );
+            },
+        );
+        temp_env::with_vars(
+            [
+                ("BEARER_TOKEN", None),
+                ("BEARER_TOKEN_FILE", Some(token_path)),
+                ("XDG_RUNTIME_DIR", Some(runtime_dir)),
+            ],
+            || {
+                assert_eq!(
+                    discover_bearer_token(Some(1234)),
+                    Some((token_path.into(), "file-token".into()))
+                );
+            },
+        );
+        temp_env::with_vars(
+            [
+                ("BEARER_TOKEN", None),
+                ("BEARER_TOKEN_FILE", Some("/nonexistent")),
+                ("XDG_RUNTIME_DIR", Some(runtime_dir)),
+            ],
+            || {
+                assert_eq!(
+                    discover_bearer_token(Some(1234)),
+                    Some((
+                        runtime_file.to_str().unwrap().into(),
+                        "runtime-token".into()
+                    ))
+                );
+                // no /tmp/bt_u4294967294 either
+                assert_eq!(discover_bearer_token(Some(u32::MAX - 1)), None);
+            },
+        );
+    }
+
+    #[test]
+    fn test_discover_condor_first() {
         test_logger();
 
+        let tmp_dir = TempDir::new().unwrap();
+        let cred = Credential {
+            access_token: "condor".into(),
+            token_type: "bearer".into(),
+            expires_in: 3600,
+            expires_at: 1.,
+            scope: vec!["storage.read:/".into()],
+            source: None,
+            ..Default::default()
+        };
+        fs::write(
+            tmp_dir.path().join("scitokens.use"),
+            serde_json::to_vec(&cred).unwrap(),
+        )
+        .unwrap();
+        let token = make_jwt(r#"{"scope":"storage.read:/","exp":4000000000}"#);
+        let data_dir = TempDir::new().unwrap();
+
+        temp_env::with_vars(
+            [
+                ("_CONDOR_CREDS", Some(tmp_dir.path().to_str().unwrap())),
+                ("XDG_DATA_HOME", Some(data_dir.path().to_str().unwrap())),
+                ("BEARER_TOKEN", Some(token.as_str())),
+            ],
+            || {
+                let creds = Credentials::discover().unwrap();
+                let tokens: Vec<_> = creds.iter().map(|c| c.access_token.as_str()).collect();
+                assert_eq!(tokens, vec!["condor", token.as_str()]);
+
+                // then tokens saved by `token get`
+                let token_dir = config::user_token_dir().unwrap();
+                fs::create_dir_all(&token_dir).unwrap();
+                let saved = Credential {
+                    access_token: "saved".into(),
+                    ..cred.clone()
+                };
+                fs::write(
+                    token_dir.join("issuer.use"),
+                    serde_json::to_vec(&saved).unwrap(),
+                )
+                .unwrap();
+                let creds = Credentials::discover().unwrap();
+                let tokens: Vec<_> = creds.iter().map(|c| c.access_token.as_str()).collect();
+                assert_eq!(tokens, vec!["condor", "saved", token.as_str()]);
+            },
+        );
+        temp_env::with_vars(
+            [
+                ("_CONDOR_CREDS", None),
+                ("XDG_DATA_HOME", Some(tmp_dir.path().to_str().unwrap())),
+                ("BEARER_TOKEN", Some(token.as_str())),
+            ],
+            || {
+                assert_eq!(Credentials::discover().unwrap().iter().count(), 1);
+            },
+        );
+    }
+
+    #[test]
+    fn test_credential_parse() {
+        // HTCondor's format
+        let cred = Credential::parse(
+            r#"{"access_token":"token","token_type":"bearer","expires_in":3600,"expires_at":1000.0,"scope":["storage.read:/"]}"#,
+        )
+        .unwrap();
+        assert_eq!(cred.scope, vec!["storage.read:/"]);
+        assert_eq!(cred.expires_at, 1000.);
+
+        // an OAuth2 token response, with the rest from the JWT
+        let token = make_jwt(
+            r#"{"iss":"https://issuer","aud":"https://wlcg.cern.ch/jwt/v1/any","scope":"storage.read:/ignored","exp":4000000000}"#,
+        );
+        let cred = Credential::parse(&format!(
+            r#"{{"access_token":"{}","scope":"storage.read:/ storage.create:/out","refresh_token":"refresh","id_token":"ignored"}}"#,
+            token
+        ))
+        .unwrap();
+        assert_eq!(cred.token_type, "bearer");
+        assert_eq!(cred.scope, vec!["storage.read:/", "storage.create:/out"]);
+        assert_eq!(cred.refresh_token.as_deref(), Some("refresh"));
+        assert_eq!(cred.iss.as_deref(), Some("https://issuer"));
+        assert_eq!(cred.aud, vec!["https://wlcg.cern.ch/jwt/v1/any"]);
+        assert_eq!(cred.expires_at, 4000000000.);
+        assert!(cred.expires_in > 0);
+
+        // just a JWT
+        let cred = Credential::parse(&format!(r#"{{"access_token":"{}"}}"#, token)).unwrap();
+        assert_eq!(cred.scope, vec!["storage.read:/ignored"]);
+
+        assert!(Credential::parse(r#"{"scope":"storage.read:/"}"#).is_err());
+
+        // an opaque token expires after expires_in, or not at all
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_secs_f32();
-        let test_cred = Credential {
-            access_token: "token".into(),
-            token_type: "bearer".into(),
-            expires_in: 3600,
-            expires_at: now + 3600.,
-            scope: vec![
-                "storage.read:/read/scope".into(),
-                "storage.modify:/write/scope".into(),
+        let cred = Credential::parse(r#"{"access_token":"opaque","expires_in":100000}"#).unwrap();
+        assert!((cred.expires_at - now - 100000.).abs() < 5000.);
+        let cred = Credential::parse(r#"{"access_token":"opaque"}"#).unwrap();
+        assert_eq!(cred.expires_at, NO_EXPIRY);
+    }
+
+    #[test]
+    fn test_read_file_expires_in() {
+        let tmp_dir = TempDir::new().unwrap();
+        let path = tmp_dir.path().join("scitokens.use");
+        fs::write(&path, r#"{"access_token":"opaque","expires_in":100000}"#).unwrap();
+        let written = SystemTime::now() - Duration::from_secs(200000);
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(written)
+            .unwrap();
+
+        let cred = Credential::read_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(cred.source.as_deref(), path.to_str());
+        let written = written.duration_since(UNIX_EPOCH).unwrap().as_secs_f32();
+        assert!((cred.expires_at - written - 100000.).abs() < 5000.);
+    }
+
+    #[test]
+    fn test_from_dir_skips_bad_files() {
+        test_logger();
+
+        let tmp_dir = TempDir::new().unwrap();
+        fs::write(
+            tmp_dir.path().join("good.use"),
+            r#"{"access_token":"token","scope":"storage.read:/"}"#,
+        )
+        .unwrap();
+        fs::write(tmp_dir.path().join("bad.use"), "not json").unwrap();
+
+        let creds = Credentials::from_dir(tmp_dir.path().to_str().unwrap()).unwrap();
+        let tokens: Vec<_> = creds.iter().map(|c| c.access_token.as_str()).collect();
+        assert_eq!(tokens, vec!["token"]);
+
+        // with nothing usable, discover says what it skipped
+        fs::remove_file(tmp_dir.path().join("good.use")).unwrap();
+        let data_dir = TempDir::new().unwrap();
+        temp_env::with_vars(
+            [
+                ("_CONDOR_CREDS", Some(tmp_dir.path().to_str().unwrap())),
+                ("XDG_DATA_HOME", Some(data_dir.path().to_str().unwrap())),
+                ("XDG_RUNTIME_DIR", Some(data_dir.path().to_str().unwrap())),
+                ("BEARER_TOKEN", None),
+                ("BEARER_TOKEN_FILE", None),
             ],
+            || {
+                let err = Credentials::discover().err().unwrap().to_string();
+                assert!(!err.contains("_CONDOR_CREDS is not set"), "{}", err);
+                assert!(err.contains("bad.use"), "{}", err);
+            },
+        );
+    }
+
+    #[test]
+    fn test_from_token() {
+        test_logger();
+
+        let info = PelicanInfo {
+            origins: vec!["https://origin".into()],
+            osdf_prefix: "osdf://".into(),
         };
+        let token = make_jwt(r#"{"scope":"storage.read:/data","exp":4000000000}"#);
+        let creds = Credentials::from_token(&token, "--token", false).unwrap();
+        assert!(
+            creds
+                .get_cred_for_url("osdf:///data/file", &["storage.read"], &info)
+                .is_ok()
+        );
+        assert!(
+            creds
+                .get_cred_for_url("osdf:///other/file", &["storage.read"], &info)
+                .is_err()
+        );
+
+        let creds = Credentials::from_token(&token, "--token", true).unwrap();
+        assert!(
+            creds
+                .get_cred_for_url("osdf:///other/file", &["storage.create"], &info)
+                .is_ok()
+        );
+
+        // an opaque token has no scopes to check
+        assert!(Credentials::from_token("opaque", "--token", false).is_err());
+        let creds = Credentials::from_token("opaque", "--token", true).unwrap();
+        let cred = creds
+            .get_cred_for_url("osdf:///any/file", &["storage.read"], &info)
+            .unwrap();
+        assert_eq!(cred.access_token, "opaque");
+
+        let tmp_file = NamedTempFile::new().unwrap();
+        fs::write(tmp_file.path(), format!("{}\n", token)).unwrap();
+        let creds = Credentials::from_token_file(tmp_file.path().to_str().unwrap(), false).unwrap();
+        assert_eq!(creds.iter().next().unwrap().access_token, token);
+        fs::write(tmp_file.path(), "\n").unwrap();
+        assert!(Credentials::from_token_file(tmp_file.path().to_str().unwrap(), false).is_err());
+    }
+
+    #[test]
+    fn test_get_correct_cred() {
+        test_logger();
 
-        let creds = Credentials(vec![test_cred.clone()]);
+        let test_cred = test_cred(&["storage.read:/read/scope", "storage.modify:/write/scope"]);
+
+        let creds = Credentials::new(vec![test_cred.clone()]);
 
         let file_path = NamedTempFile::new().ok().unwrap();
         let mut transfer = Transfer::new(
@@ -216,17 +988,11 @@ mod tests {
             .unwrap()
             .as_secs_f32();
         let test_cred = Credential {
-            access_token: "token".into(),
-            token_type: "bearer".into(),
-            expires_in: 3600,
             expires_at: now - 3600.,
-            scope: vec![
-                "storage.read:/read/scope".into(),
-                "storage.modify:/write/scope".into(),
-            ],
+            ..test_cred(&["storage.read:/read/scope", "storage.modify:/write/scope"])
         };
 
-        let creds = Credentials(vec![test_cred.clone()]);
+        let creds = Credentials::new(vec![test_cred.clone()]);
 
         let file_path = NamedTempFile::new().ok().unwrap();
         let transfer = Transfer::new(
@@ -242,4 +1008,288 @@ mod tests {
         let out_cred = creds.get_correct_cred(&transfer, &info).unwrap();
         assert_eq!(out_cred, &test_cred);
     }
+
+    #[test]
+    fn test_get_cred_for_urls() {
+        test_logger();
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f32();
+        let read_cred = Credential {
+            access_token: "read".into(),
+            token_type: "bearer".into(),
+            expires_in: 3600,
+            expires_at: now + 3600.,
+            scope: vec!["storage.read:/data".into()],
+            source: None,
+            ..Default::default()
+        };
+        let both_cred = Credential {
+            access_token: "both".into(),
+            token_type: "bearer".into(),
+            expires_in: 3600,
+            expires_at: now + 3600.,
+            scope: vec![
+                "storage.read:/data".into(),
+                "storage.modify:/data/out".into(),
+            ],
+            source: None,
+            ..Default::default()
+        };
+
+        let creds = Credentials::new(vec![read_cred.clone(), both_cred.clone()]);
+        let info = PelicanInfo {
+            origins: vec!["http://origin".into()],
+            osdf_prefix: "url://namespace".into(),
+        };
+
+        let out_cred = creds
+            .get_cred_for_urls(
+                &[
+                    ("url://namespace/data/in/file.bin", &["storage.read"]),
+                    ("url://namespace/data/out/file.bin", &["storage.modify"]),
+                ],
+                &info,
+            )
+            .unwrap();
+        assert_eq!(out_cred, &both_cred);
+
+        assert!(
+            creds
+                .get_cred_for_urls(
+                    &[
+                        ("url://namespace/data/in/file.bin", &["storage.read"]),
+                        ("url://namespace/data/in/copy.bin", &["storage.modify"]),
+                    ],
+                    &info,
+                )
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_scope_path_covers() {
+        assert_eq!(
+            scope_path_covers("/read/scope", "/read/scope/file"),
+            Some(11)
+        );
+        assert_eq!(
+            scope_path_covers("/read/scope/", "/read/scope/file"),
+            Some(11)
+        );
+        assert_eq!(scope_path_covers("/read/scope", "/read/scope"), Some(11));
+        assert_eq!(scope_path_covers("/read/scope", "/read/scopeX/file"), None);
+        assert_eq!(scope_path_covers("/", "/anything"), Some(0));
+        assert_eq!(scope_path_covers("/other", "/read/scope/file"), None);
+    }
+
+    #[test]
+    fn test_most_specific_cred() {
+        test_logger();
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f32();
+        let cred = |token: &str, scope: &str, expires_at: f32| Credential {
+            access_token: token.into(),
+            scope: vec![scope.into()],
+            expires_at,
+            ..Default::default()
+        };
+        let info = PelicanInfo {
+            origins: vec!["http://origin".into()],
+            osdf_prefix: "osdf://".into(),
+        };
+        let chosen = |creds: &Credentials, url: &str, verb: Verb| {
+            let transfer = Transfer::new(url.into(), "file".into(), verb);
+            creds
+                .get_correct_cred(&transfer, &info)
+                .map(|c| c.access_token.clone())
+                .ok()
+        };
+
+        let creds = Credentials::new(vec![
+            cred("root", "storage.read:/", now + 20000.),
+            cred("icecube", "storage.read:/icecube", now + 20000.),
+            cred(
+                "private",
+                "storage.read:/icecube/wipac/private",
+                now + 5000.,
+            ),
+            cred(
+                "private-later",
+                "storage.read:/icecube/wipac/private",
+                now + 10000.,
+            ),
+            cred("stage", "storage.stage:/tape", now + 5000.),
+            cred("nopath", "storage.create", now + 5000.),
+        ]);
+        assert_eq!(
+            chosen(&creds, "osdf:///icecube/wipac/private/f", Verb::Get).as_deref(),
+            Some("private-later")
+        );
+        assert_eq!(
+            chosen(&creds, "osdf:///icecube/wipac/privateX/f", Verb::Get).as_deref(),
+            Some("icecube")
+        );
+        assert_eq!(
+            chosen(&creds, "osdf:///other/f", Verb::Get).as_deref(),
+            Some("root")
+        );
+        assert_eq!(
+            chosen(&creds, "osdf:///tape/f", Verb::Get).as_deref(),
+            Some("stage")
+        );
+        assert_eq!(
+            chosen(&creds, "osdf:///any/f", Verb::Put).as_deref(),
+            Some("nopath")
+        );
+
+        let transfer = Transfer::new(
+            "osdf:///icecube/wipac/private/f".into(),
+            "f".into(),
+            Verb::Get,
+        );
+        let choice = creds.explain_correct_cred(&transfer, &info).unwrap();
+        assert_eq!(
+            choice.reasons[..4],
+            [
+                "matches, but a more specific credential was picked",
+                "matches, but a more specific credential was picked",
+                "matches, but a credential with more time left was picked",
+                "selected",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_refresh_expired_cred() {
+        test_logger();
+
+        let server = httpmock::MockServer::start();
+        let well_known = server.mock(|when, then| {
+            when.path("/.well-known/openid-configuration");
+            then.status(200).json_body(serde_json::json!({
+                "token_endpoint": server.url("/token"),
+            }));
+        });
+        let token = server.mock(|when, then| {
+            when.path("/token")
+                .form_urlencoded_tuple("refresh_token", "refresh");
+            then.status(200).json_body(serde_json::json!({
+                "access_token": "new",
+                "expires_in": 3600,
+            }));
+        });
+
+        let creds = Credentials::new(vec![Credential {
+            access_token: "old".into(),
+            scope: vec!["storage.read:/".into()],
+            expires_at: 1.,
+            refresh_token: Some("refresh".into()),
+            iss: Some(server.url("")),
+            ..Default::default()
+        }]);
+        let info = PelicanInfo {
+            origins: vec!["http://origin".into()],
+            osdf_prefix: "osdf://".into(),
+        };
+        for _ in 0..2 {
+            let cred = creds
+                .get_cred_for_url("osdf:///data/file", &["storage.read"], &info)
+                .unwrap();
+            assert_eq!(cred.access_token, "new");
+        }
+        // refreshed only once
+        well_known.assert_calls(1);
+        token.assert_calls(1);
+    }
+
+    #[test]
+    fn test_refresh_narrowed_scope() {
+        test_logger();
+
+        let server = httpmock::MockServer::start();
+        server.mock(|when, then| {
+            when.path("/.well-known/openid-configuration");
+            then.status(200).json_body(serde_json::json!({
+                "token_endpoint": server.url("/token"),
+            }));
+        });
+        server.mock(|when, then| {
+            when.path("/token");
+            then.status(200).json_body(serde_json::json!({
+                "access_token": "new",
+                "expires_in": 3600,
+                "scope": "storage.read:/other",
+            }));
+        });
+
+        let creds = Credentials::new(vec![Credential {
+            access_token: "old".into(),
+            scope: vec!["storage.read:/".into()],
+            expires_at: 1.,
+            refresh_token: Some("refresh".into()),
+            iss: Some(server.url("")),
+            ..Default::default()
+        }]);
+        let info = PelicanInfo {
+            origins: vec!["http://origin".into()],
+            osdf_prefix: "osdf://".into(),
+        };
+        let cred = creds
+            .get_cred_for_url("osdf:///data/file", &["storage.read"], &info)
+            .unwrap();
+        assert_eq!(cred.access_token, "old");
+    }
+
+    #[test]
+    fn test_explain_correct_cred() {
+        test_logger();
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f32();
+        let cred = |scope: &str, expires_at: f32| Credential {
+            expires_at,
+            ..test_cred(&[scope])
+        };
+        let creds = Credentials::new(vec![
+            cred("storage.modify:/data", now + 3600.),
+            cred("storage.read:/data", now - 3600.),
+            cred("storage.read:/data", now + 3600.),
+            cred("storage.read:/", now + 3600.),
+        ]);
+        let info = PelicanInfo {
+            origins: vec!["http://origin".into()],
+            osdf_prefix: "url://namespace".into(),
+        };
+        let transfer = Transfer::new(
+            "url://namespace/data/file.bin".into(),
+            "file.bin".into(),
+            Verb::Get,
+        );
+
+        let choice = creds.explain_correct_cred(&transfer, &info).unwrap();
+        assert_eq!(choice.selected, Some(2));
+        assert!(!choice.expired);
+        assert_eq!(
+            choice.reasons,
+            vec![
+                "rejected: no storage.read or storage.stage scope covering /data/file.bin",
+                "expired",
+                "selected",
+                "matches, but a more specific credential was picked",
+            ]
+        );
+
+        let creds = Credentials::new(vec![cred("storage.read:/data", now - 3600.)]);
+        let choice = creds.explain_correct_cred(&transfer, &info).unwrap();
+        assert_eq!(choice.selected, Some(0));
+        assert!(choice.expired);
+    }
 }
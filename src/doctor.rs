@@ -0,0 +1,379 @@
+use std::error::Error;
+use std::fs;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config;
+use crate::credentials::{self, Credential, Credentials};
+use crate::error::MyError;
+use crate::output::Reporter;
+use crate::pelican::{self, PelicanInfo};
+use crate::token;
+use crate::transfer::{Transfer, Verb, build_http_client};
+use crate::webdav;
+
+/// Tokens expiring sooner than this get a warning.
+const EXPIRY_WARNING: i64 = 600;
+
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// The result of one check.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub details: String,
+}
+
+impl Check {
+    fn new(name: &str, status: Status, details: impl Into<String>) -> Self {
+        Check {
+            name: name.to_string(),
+            status,
+            details: details.into(),
+        }
+    }
+}
+
+/// Check that the credential directory is readable, that each `*.use` file
+/// parses, and how long each token has left, as of `now` (unix seconds).
+fn check_credentials(cred_dir: &Result<String, Box<dyn Error>>, now: i64) -> Vec<Check> {
+    let dir = match cred_dir {
+        Ok(dir) => dir,
+        Err(e) => return vec![Check::new("cred dir", Status::Fail, e.to_string())],
+    };
+    if let Err(e) = fs::read_dir(dir) {
+        return vec![Check::new(
+            "cred dir",
+            Status::Fail,
+            format!("cannot read {}: {}", dir, e),
+        )];
+    }
+    let files = match credentials::get_cred_files(dir) {
+        Ok(files) => files,
+        Err(e) => return vec![Check::new("cred dir", Status::Fail, e.to_string())],
+    };
+    if files.is_empty() {
+        return vec![Check::new(
+            "cred dir",
+            Status::Fail,
+            format!("no *.use files in {}", dir),
+        )];
+    }
+
+    let mut ret = vec![Check::new(
+        "cred dir",
+        Status::Pass,
+        format!("{} ({} files)", dir, files.len()),
+    )];
+    for filename in files {
+        let name = format!("credential {}", filename);
+        let cred = match Credential::read_file(&filename) {
+            Ok(cred) => cred,
+            Err(e) => {
+                ret.push(Check::new(
+                    &name,
+                    Status::Fail,
+                    format!("cannot parse: {}", e),
+                ));
+                continue;
+            }
+        };
+        ret.push(check_expiry(&name, &cred, now));
+    }
+    ret
+}
+
+/// Fail expired credentials, and warn about ones expiring soon.
+fn check_expiry(name: &str, cred: &Credential, now: i64) -> Check {
+    let left = match token::expires_at(cred) {
+        Some(exp) => exp - now,
+        None => return Check::new(name, Status::Pass, "does not expire"),
+    };
+    if left <= 0 {
+        Check::new(
+            name,
+            Status::Fail,
+            format!("expired {} ago", token::format_duration(-left as u64)),
+        )
+    } else if left < EXPIRY_WARNING {
+        Check::new(
+            name,
+            Status::Warn,
+            format!("expires in {}", token::format_duration(left as u64)),
+        )
+    } else {
+        Check::new(
+            name,
+            Status::Pass,
+            format!("valid for {}", token::format_duration(left as u64)),
+        )
+    }
+}
+
+/// Check the bearer token found by WLCG token discovery, if any.
+fn check_bearer_token(found: Option<(String, String)>, now: i64) -> Check {
+    match found {
+        None => Check::new(
+            "bearer token",
+            Status::Warn,
+            "none in BEARER_TOKEN, BEARER_TOKEN_FILE, $XDG_RUNTIME_DIR/bt_u<uid>, or /tmp/bt_u<uid>",
+        ),
+        Some((source, token)) => {
+            let name = format!("bearer token {}", source);
+            match Credential::from_jwt(&token, &source) {
+                Ok(cred) => check_expiry(&name, &cred, now),
+                Err(e) => Check::new(&name, Status::Fail, format!("cannot parse: {}", e)),
+            }
+        }
+    }
+}
+
+fn client_builder() -> reqwest::blocking::ClientBuilder {
+    reqwest::blocking::ClientBuilder::new()
+        .redirect(reqwest::redirect::Policy::none())
+        .connect_timeout(Duration::from_secs(config::get().connect_timeout.value))
+        .timeout(Duration::from_secs(config::get().timeout.value))
+}
+
+/// The innermost cause of an error, which is where TLS failures are described.
+fn root_cause(e: &(dyn Error + 'static)) -> String {
+    let mut cause = e;
+    while let Some(source) = cause.source() {
+        cause = source;
+    }
+    cause.to_string()
+}
+
+/// Check that the director answers, and that its certificate validates
+/// against the system trust store alone.
+fn check_director(director: &str) -> Vec<Check> {
+    let client = client_builder().build().expect("HTTP Client should build");
+    let mut ret = vec![match client.get(director).send() {
+        Ok(r) => Check::new(
+            "director",
+            Status::Pass,
+            format!("{} answered with status {}", director, r.status().as_u16()),
+        ),
+        Err(e) => Check::new(
+            "director",
+            Status::Fail,
+            format!("cannot contact {}: {}", director, root_cause(&e)),
+        ),
+    }];
+
+    if ret[0].status == Status::Fail {
+        ret.push(Check::new(
+            "tls",
+            Status::Warn,
+            "not checked, the director cannot be reached",
+        ));
+        return ret;
+    }
+    if !director.starts_with("https://") {
+        ret.push(Check::new(
+            "tls",
+            Status::Warn,
+            format!("not checked, {} is not https", director),
+        ));
+        return ret;
+    }
+    let client = client_builder()
+        .tls_built_in_webpki_certs(false)
+        .tls_built_in_native_certs(true)
+        .build()
+        .expect("HTTP Client should build");
+    ret.push(match client.get(director).send() {
+        Ok(_) => Check::new(
+            "tls",
+            Status::Pass,
+            "director certificate validates against the system trust store",
+        ),
+        Err(e) => Check::new(
+            "tls",
+            Status::Fail,
+            format!("with the system trust store: {}", root_cause(&e)),
+        ),
+    });
+    ret
+}
+
+/// Check that a HEAD of `url` through the chosen origin succeeds.
+fn check_origin(creds: &Credentials, url: &str) -> Check {
+    let result = || -> Result<Check, Box<dyn Error>> {
+        let info = PelicanInfo::from_url(url)?;
+        let transfer = Transfer::new(url.to_string(), String::new(), Verb::Get);
+        let plan = transfer.plan(creds, &info)?;
+        let client = build_http_client(creds.get_correct_cred(&transfer, &info)?)?;
+        let stat = webdav::stat(&client, &plan.origin_url)?;
+        Ok(if stat.exists {
+            Check::new(
+                "origin",
+                Status::Pass,
+                format!("HEAD {} succeeded", plan.origin_url),
+            )
+        } else {
+            Check::new(
+                "origin",
+                Status::Warn,
+                format!(
+                    "{} answered, but the object does not exist",
+                    plan.origin_url
+                ),
+            )
+        })
+    };
+    result().unwrap_or_else(|e| Check::new("origin", Status::Fail, e.to_string()))
+}
+
+/// Run every check, print a report, and fail if any check failed.
+/// Bearer tokens are only looked for without `cred_dir`, like transfers do.
+/// The director and origin are checked against `url`, if given.
+pub fn doctor(
+    cred_dir: Option<&str>,
+    url: Option<&str>,
+    report: &Reporter,
+) -> Result<(), Box<dyn Error>> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let mut checks = Vec::new();
+    let creds = match cred_dir {
+        Some(dir) => {
+            checks.extend(check_credentials(&Ok(dir.to_string()), now));
+            Credentials::from_dir(dir)
+        }
+        None => {
+            let bearer_token = credentials::discover_bearer_token(credentials::current_uid());
+            match credentials::get_cred_dir() {
+                // outside of HTCondor, a bearer token is enough
+                Err(e) if bearer_token.is_some() => {
+                    checks.push(Check::new("cred dir", Status::Warn, e.to_string()))
+                }
+                cred_dir => checks.extend(check_credentials(&cred_dir, now)),
+            }
+            checks.push(check_bearer_token(bearer_token, now));
+            Credentials::discover()
+        }
+    };
+
+    let director = match url {
+        Some(url) => pelican::get_director_url(url),
+        None => Some(config::get().osdf_director.value.clone()),
+    };
+    match director {
+        Some(director) => checks.extend(check_director(&director)),
+        None => checks.push(Check::new(
+            "director",
+            Status::Fail,
+            "cannot find the director for the url",
+        )),
+    }
+
+    match url {
+        Some(url) => {
+            checks.push(match creds {
+                Ok(creds) => check_origin(&creds, url),
+                Err(e) => Check::new("origin", Status::Fail, e.to_string()),
+            });
+        }
+        None => checks.push(Check::new(
+            "origin",
+            Status::Warn,
+            "not checked, give a test url to check it",
+        )),
+    }
+
+    for check in checks.iter() {
+        if report.is_json() {
+            report.json(check)?;
+        } else {
+            let status = format!("{:?}", check.status).to_uppercase();
+            println!("[{:<4}] {}: {}", status, check.name, check.details);
+        }
+    }
+    let failed = checks.iter().filter(|c| c.status == Status::Fail).count();
+    if failed > 0 {
+        return Err(Box::new(MyError::Generic(format!(
+            "{} of {} checks failed",
+            failed,
+            checks.len()
+        ))));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use httpmock::prelude::*;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::logging::{test_cred, test_logger};
+
+    fn write_cred(dir: &TempDir, name: &str, expires_at: f32) {
+        let cred = Credential {
+            expires_at,
+            ..test_cred(&["storage.read:/"])
+        };
+        fs::write(dir.path().join(name), serde_json::to_vec(&cred).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_check_credentials() {
+        test_logger();
+        let tmp_dir = TempDir::new().unwrap();
+        let dir = tmp_dir.path().to_str().unwrap().to_string();
+
+        let checks = check_credentials(&Ok(dir.clone()), 1000);
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks[0].status, Status::Fail);
+
+        write_cred(&tmp_dir, "good.use", 5000.);
+        write_cred(&tmp_dir, "soon.use", 1100.);
+        write_cred(&tmp_dir, "old.use", 900.);
+        fs::write(tmp_dir.path().join("bad.use"), "{}").unwrap();
+        fs::write(tmp_dir.path().join("ignored.txt"), "{}").unwrap();
+
+        let checks = check_credentials(&Ok(dir), 1000);
+        let status = |file: &str| {
+            checks
+                .iter()
+                .find(|c| c.name.ends_with(file))
+                .unwrap()
+                .status
+        };
+        assert_eq!(checks.len(), 5);
+        assert_eq!(checks[0].status, Status::Pass);
+        assert_eq!(status("good.use"), Status::Pass);
+        assert_eq!(status("soon.use"), Status::Warn);
+        assert_eq!(status("old.use"), Status::Fail);
+        assert_eq!(status("bad.use"), Status::Fail);
+
+        let checks = check_credentials(&Err("_CONDOR_CREDS env variable not set".into()), 1000);
+        assert_eq!(checks[0].status, Status::Fail);
+    }
+
+    #[test]
+    fn test_check_director() {
+        test_logger();
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(GET).path("/api/v1.0/director/origin");
+            then.status(404);
+        });
+
+        let checks = check_director(&server.url("/api/v1.0/director/origin"));
+        mock.assert();
+        assert_eq!(checks[0].status, Status::Pass);
+        assert!(checks[0].details.contains("404"));
+        assert_eq!(checks[1].status, Status::Warn);
+
+        let checks = check_director("http://127.0.0.1:1/");
+        assert_eq!(checks[0].status, Status::Fail);
+        assert_eq!(checks[1].status, Status::Warn);
+    }
+}
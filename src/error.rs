@@ -1,12 +1,23 @@
 use std::error::Error;
 use std::fmt;
 
-#[derive(Debug)] // Required for the `Error` trait
+// `Debug` is required for the `Error` trait, `Serialize` for JSON error reports
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(tag = "class", content = "details")]
 pub enum MyError {
     Credentials(String),
     Transfer(String),
     Pelican(String),
-    #[allow(dead_code)]
+    Http {
+        url: String,
+        status: Option<u16>,
+        details: String,
+    },
+    Plugin(String),
+    /// A transfer from or to a pipe, which cannot be retried
+    Stream(String),
+    /// A bad config file
+    Config(String),
     Generic(String),
 }
 
@@ -18,6 +29,19 @@ impl fmt::Display for MyError {
             MyError::Credentials(details) => write!(f, "CredenialsError: {details}"),
             MyError::Transfer(details) => write!(f, "TransferError: {details}"),
             MyError::Pelican(details) => write!(f, "PelicanError: {details}"),
+            MyError::Http {
+                url,
+                status: Some(status),
+                details,
+            } => write!(f, "HttpError: {url} returned status {status}: {details}"),
+            MyError::Http {
+                url,
+                status: None,
+                details,
+            } => write!(f, "HttpError: {url}: {details}"),
+            MyError::Plugin(details) => write!(f, "PluginError: {details}"),
+            MyError::Stream(details) => write!(f, "StreamError: {details}"),
+            MyError::Config(details) => write!(f, "ConfigError: {details}"),
             MyError::Generic(details) => write!(f, "GenericError: {details}"),
         }
     }
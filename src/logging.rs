@@ -42,3 +42,33 @@ pub fn test_logger() {
             .unwrap();
     });
 }
+
+/// An unexpired credential with these scopes, for tests.
+#[cfg(test)]
+pub fn test_cred(scopes: &[&str]) -> crate::credentials::Credential {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs_f32();
+    crate::credentials::Credential {
+        access_token: "token".into(),
+        token_type: "bearer".into(),
+        expires_in: 3600,
+        expires_at: now + 3600.,
+        scope: scopes.iter().map(|s| s.to_string()).collect(),
+        source: None,
+        ..Default::default()
+    }
+}
+
+/// An unsigned JWT with these claims, for tests.
+#[cfg(test)]
+pub fn make_jwt(claims: &str) -> String {
+    use base64::Engine;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    format!(
+        "{}.{}.sig",
+        URL_SAFE_NO_PAD.encode(r#"{"alg":"RS256"}"#),
+        URL_SAFE_NO_PAD.encode(claims)
+    )
+}
@@ -1,8 +1,19 @@
+mod batch;
+mod classad;
+mod config;
 mod credentials;
+mod doctor;
 mod error;
 mod logging;
+mod oauth;
+mod object;
+mod output;
 mod pelican;
+mod plugin;
+mod sync;
+mod token;
 mod transfer;
+mod webdav;
 
 use std::backtrace::Backtrace;
 use std::error::Error;
@@ -17,8 +28,49 @@ struct Cli {
     #[arg(short, long)]
     log_level: Option<String>,
 
-    #[arg(short, long, default_value_t = 1)]
-    retries: u8,
+    /// Retries after a failed attempt [default: 4]
+    #[arg(short, long)]
+    retries: Option<u32>,
+
+    /// Seconds allowed for each request [default: 3600]
+    #[arg(long)]
+    timeout: Option<u64>,
+
+    /// Seconds allowed to connect [default: 60]
+    #[arg(long)]
+    connect_timeout: Option<u64>,
+
+    /// The director to ask for `osdf://` urls
+    #[arg(long)]
+    director: Option<String>,
+
+    /// Read this config file after the system and user ones
+    #[arg(long)]
+    config: Option<String>,
+
+    /// Use only this token, instead of discovering credentials
+    #[arg(long, conflicts_with = "token_file")]
+    token: Option<String>,
+
+    /// Use only the token in this file, instead of discovering credentials
+    #[arg(long)]
+    token_file: Option<String>,
+
+    /// Use a credential whatever its scopes say, or a token that is not a JWT
+    #[arg(long)]
+    skip_scope_check: bool,
+
+    /// Print transfer statistics as JSON on stdout
+    #[arg(long)]
+    stats: bool,
+
+    /// Resolve urls, origins, and credentials, but transfer nothing
+    #[arg(long)]
+    dry_run: bool,
+
+    /// How to print results on stdout
+    #[arg(long, value_enum, default_value_t = output::OutputFormat::Text)]
+    output: output::OutputFormat,
 
     #[command(subcommand)]
     command: Commands,
@@ -27,6 +79,89 @@ struct Cli {
 #[derive(Subcommand, Debug)]
 enum Commands {
     Object(ObjectCommands),
+    Token(TokenCommands),
+    Namespace(NamespaceCommands),
+    Config(ConfigCommands),
+    /// Check credentials, the director, TLS, and an origin, and report problems
+    Doctor {
+        /// A url to check the director and origin with
+        url: Option<String>,
+        /// Read `*.use` credential files from this directory instead of `_CONDOR_CREDS`
+        #[arg(long)]
+        cred_dir: Option<String>,
+    },
+}
+
+#[derive(Parser, Debug)]
+struct ConfigCommands {
+    #[command(subcommand)]
+    command: ConfigSubcommands,
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigSubcommands {
+    /// Print the merged configuration, and where each value came from
+    Show,
+}
+
+#[derive(Parser, Debug)]
+struct NamespaceCommands {
+    #[command(subcommand)]
+    command: NamespaceSubcommands,
+}
+
+#[derive(Subcommand, Debug)]
+enum NamespaceSubcommands {
+    /// Show what the director says about the namespace of a url
+    Info {
+        url: String,
+        /// Print the result as JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Parser, Debug)]
+struct TokenCommands {
+    #[command(subcommand)]
+    command: TokenSubcommands,
+}
+
+#[derive(Subcommand, Debug)]
+enum TokenSubcommands {
+    /// Show the claims of each credential, and which one a url would use
+    Inspect {
+        /// Explain which credential would be used for this url
+        url: Option<String>,
+        /// The kind of transfer to pick a credential for
+        #[arg(long, value_enum, default_value_t = transfer::Verb::Get, requires = "url")]
+        verb: transfer::Verb,
+        /// Read `*.use` credential files from this directory instead of `_CONDOR_CREDS`
+        #[arg(long)]
+        cred_dir: Option<String>,
+    },
+    /// Get a token with the OAuth2 device flow, and save it for later commands
+    Get {
+        /// The token issuer; without it, ask the director for the url's issuer
+        #[arg(long, required_unless_present = "url")]
+        issuer: Option<String>,
+        /// Find the issuer of this url's namespace
+        #[arg(long)]
+        url: Option<String>,
+        /// Scopes to ask for, such as `storage.read:/icecube`
+        #[arg(long, required = true)]
+        scope: Vec<String>,
+        /// The OAuth2 client registered with the issuer
+        #[arg(long)]
+        client_id: String,
+        /// The secret of a confidential client
+        #[arg(long, env = oauth::CLIENT_SECRET_ENV, hide_env_values = true)]
+        client_secret: Option<String>,
+        /// Read the client secret from this file, instead of --client-secret
+        // no conflicts_with: clap would count the env var as a conflict
+        #[arg(long)]
+        client_secret_file: Option<String>,
+    },
 }
 
 #[derive(Parser, Debug)]
@@ -37,51 +172,499 @@ struct ObjectCommands {
 
 #[derive(Subcommand, Debug)]
 enum ObjectSubcommands {
-    Get { url: String, filename: String },
-    Put { filename: String, url: String },
+    Get {
+        #[arg(required_unless_present = "from_file")]
+        url: Option<String>,
+        /// Local file, or `-` for stdout; a directory if the url has globs
+        #[arg(required_unless_present = "from_file")]
+        filename: Option<String>,
+        /// Download a whole collection into the `filename` directory
+        #[arg(short, long)]
+        recursive: bool,
+        /// Only download files matching this glob (can be repeated)
+        #[arg(long, requires = "recursive")]
+        include: Vec<String>,
+        /// Skip files matching this glob (can be repeated)
+        #[arg(long, requires = "recursive")]
+        exclude: Vec<String>,
+        /// Read `<url> <local path>` lines from this manifest file
+        #[arg(long, conflicts_with_all = ["url", "filename", "recursive"])]
+        from_file: Option<String>,
+        /// How many manifest transfers to run at once
+        #[arg(long, default_value_t = 1, requires = "from_file", value_parser = clap::value_parser!(u16).range(1..))]
+        parallel: u16,
+    },
+    Put {
+        /// Local file, or `-` for stdin
+        #[arg(required_unless_present = "from_file")]
+        filename: Option<String>,
+        #[arg(required_unless_present = "from_file")]
+        url: Option<String>,
+        /// Upload the whole `filename` directory
+        #[arg(short, long)]
+        recursive: bool,
+        /// Only upload files matching this glob (can be repeated)
+        #[arg(long, requires = "recursive")]
+        include: Vec<String>,
+        /// Skip files matching this glob (can be repeated)
+        #[arg(long, requires = "recursive")]
+        exclude: Vec<String>,
+        /// What to do with symlinks
+        #[arg(long, value_enum, default_value_t = object::SymlinkPolicy::Follow, requires = "recursive")]
+        symlinks: object::SymlinkPolicy,
+        /// Read `<url> <local path>` lines from this manifest file
+        #[arg(long, conflicts_with_all = ["url", "filename", "recursive"])]
+        from_file: Option<String>,
+        /// How many manifest transfers to run at once
+        #[arg(long, default_value_t = 1, requires = "from_file", value_parser = clap::value_parser!(u16).range(1..))]
+        parallel: u16,
+    },
+    /// List the contents of a collection
+    Ls {
+        url: String,
+        /// Long format, with type, size, and modification time
+        #[arg(short)]
+        long: bool,
+        /// Print the listing as JSON
+        #[arg(long)]
+        json: bool,
+        /// How deep to list
+        #[arg(long, default_value = "1", value_parser = ["1", "infinity"])]
+        depth: String,
+        /// List recursively, same as `--depth infinity`
+        #[arg(short = 'R')]
+        recursive: bool,
+    },
+    /// Show whether an object exists, with its size, mtime, ETag, and checksums
+    Stat {
+        url: String,
+        /// Print the result as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Remove an object
+    Rm {
+        url: String,
+        /// Remove collections and their contents
+        #[arg(short, long)]
+        recursive: bool,
+    },
+    /// Copy an object to another url in the same namespace, on the server
+    Cp {
+        src: String,
+        dst: String,
+        /// Fail instead of overwriting an existing destination
+        #[arg(short, long)]
+        no_clobber: bool,
+    },
+    /// Move an object to another url in the same namespace
+    Mv {
+        src: String,
+        dst: String,
+        /// Fail instead of overwriting an existing destination
+        #[arg(short, long)]
+        no_clobber: bool,
+    },
+    /// Copy only missing or changed files between a local directory and a collection
+    Sync {
+        src: String,
+        dst: String,
+        /// Compare adler32 checksums instead of modification times
+        #[arg(short, long)]
+        checksum: bool,
+        /// Delete files in the destination that are not in the source
+        #[arg(long)]
+        delete: bool,
+        /// Print what would be done, without doing it
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+    /// Create a collection
+    Mkdir {
+        url: String,
+        /// Create missing parent collections, and succeed if it already exists
+        #[arg(short, long)]
+        parents: bool,
+    },
 }
 
-fn run() -> Result<(), Box<dyn Error>> {
-    let cli = Cli::parse();
+/// Exit codes for errors a caller may want to handle specially,
+/// clear of the 2 clap exits with on usage errors
+const EXIT_NOT_FOUND: u8 = 4;
+const EXIT_FORBIDDEN: u8 = 5;
+
+fn run_transfer(
+    report: &output::Reporter,
+    creds: &credentials::Credentials,
+    url: &str,
+    filename: &str,
+    verb: transfer::Verb,
+) -> Result<(), Box<dyn Error>> {
+    let transfer = transfer::Transfer::new(url.to_string(), filename.to_string(), verb);
+    // get Pelican info, then do transfer
+    let result =
+        pelican::PelicanInfo::from_url(url).and_then(|origin| transfer.execute(creds, &origin));
+
+    // keep stdout for the data when streaming it
+    let report = output::Reporter {
+        stderr: transfer.writes_stdout(),
+        ..*report
+    };
+    report.transfer(
+        url,
+        filename,
+        &format!("{:?}", verb).to_lowercase(),
+        &result,
+    )?;
+    result.map(|_| ())
+}
+
+fn run_batch(
+    report: &output::Reporter,
+    creds: &credentials::Credentials,
+    manifest: &str,
+    mode: transfer::Verb,
+    parallel: u16,
+) -> Result<(), Box<dyn Error>> {
+    let entries = batch::parse_manifest(&std::fs::read_to_string(manifest)?)?;
+    batch::run_batch(creds, &entries, mode, parallel as usize, report)
+}
 
+impl Cli {
+    /// The `--token` or `--token-file` credential, or else the discovered ones.
+    fn credentials(&self) -> Result<credentials::Credentials, Box<dyn Error>> {
+        use credentials::Credentials;
+        if let Some(token) = &self.token {
+            return Credentials::from_token(token, "--token", self.skip_scope_check);
+        }
+        if let Some(path) = &self.token_file {
+            return Credentials::from_token_file(path, self.skip_scope_check);
+        }
+        let mut creds = Credentials::discover()?;
+        creds.set_skip_scope_check(self.skip_scope_check);
+        Ok(creds)
+    }
+
+    fn reporter(&self) -> output::Reporter {
+        output::Reporter {
+            format: self.output,
+            stats: self.stats,
+            stderr: false,
+        }
+    }
+}
+
+fn run(cli: &Cli) -> Result<(), Box<dyn Error>> {
     // set up logging
-    let log_level = match cli.log_level {
+    let log_level = match &cli.log_level {
         None => logging::LOG_DEFAULT_LEVEL.to_string(),
-        Some(level) => level,
+        Some(level) => level.clone(),
     };
     let _log_handle = logging::configure_logging(log_level.as_str());
 
-    // get credentials
-    let creds = credentials::Credentials::from_condor()?;
+    let overrides = config::CliOverrides {
+        director: cli.director.clone(),
+        timeout: cli.timeout,
+        connect_timeout: cli.connect_timeout,
+        retries: cli.retries,
+    };
+    let paths = config::config_paths(cli.config.as_deref());
+    config::init(config::Config::load(&paths, &overrides)?);
 
-    // get transfer info
-    let transfer = match &cli.command {
-        Commands::Object(sub) => match &sub.command {
-            ObjectSubcommands::Get { url, filename } => {
-                transfer::Transfer::new(url.clone(), filename.clone(), transfer::Verb::Get)
+    match &cli.command {
+        Commands::Object(sub) => run_object(cli, &sub.command),
+        Commands::Token(sub) => match &sub.command {
+            TokenSubcommands::Inspect {
+                url,
+                verb,
+                cred_dir,
+            } => {
+                let creds = match cred_dir {
+                    Some(dir) => credentials::Credentials::from_dir(dir)?,
+                    None => cli.credentials()?,
+                };
+                token::inspect(&creds, url.as_deref(), *verb, &cli.reporter())
             }
-            ObjectSubcommands::Put { filename, url } => {
-                transfer::Transfer::new(url.clone(), filename.clone(), transfer::Verb::Put)
+            TokenSubcommands::Get {
+                issuer,
+                url,
+                scope,
+                client_id,
+                client_secret,
+                client_secret_file,
+            } => {
+                let client_secret = match (client_secret_file, client_secret) {
+                    (Some(path), _) => Some(oauth::read_secret_file(path)?),
+                    (None, Some(secret)) => Some(secret.clone()),
+                    (None, None) => oauth::client_secret()?,
+                };
+                token::get(
+                    issuer.as_deref(),
+                    url.as_deref(),
+                    scope,
+                    &oauth::OAuthClient {
+                        id: client_id,
+                        secret: client_secret.as_deref(),
+                    },
+                    &cli.reporter(),
+                )
             }
         },
+        Commands::Namespace(sub) => match &sub.command {
+            NamespaceSubcommands::Info { url, json } => {
+                pelican::print_namespace_info(url, &cli.reporter().with_json(*json))
+            }
+        },
+        Commands::Doctor { url, cred_dir } => {
+            doctor::doctor(cred_dir.as_deref(), url.as_deref(), &cli.reporter())
+        }
+        Commands::Config(sub) => match &sub.command {
+            ConfigSubcommands::Show => {
+                let report = cli.reporter();
+                if report.is_json() {
+                    report.json(config::get())
+                } else {
+                    config::get().show();
+                    Ok(())
+                }
+            }
+        },
+    }
+}
+
+/// The transfers to resolve for `--dry-run`, or None if the command does its own dry run.
+fn dry_run_transfers(
+    command: &ObjectSubcommands,
+) -> Result<Option<Vec<transfer::Transfer>>, Box<dyn Error>> {
+    use transfer::{Transfer, Verb};
+    let one = |url: &str, verb: Verb| Transfer::new(url.to_string(), String::new(), verb);
+    let manifest = |path: &str, verb: Verb| -> Result<Vec<Transfer>, Box<dyn Error>> {
+        Ok(batch::parse_manifest(&std::fs::read_to_string(path)?)?
+            .into_iter()
+            .map(|e| Transfer::new(e.url, e.path, verb))
+            .collect())
     };
+    Ok(Some(match command {
+        ObjectSubcommands::Get {
+            from_file: Some(path),
+            ..
+        } => manifest(path, Verb::Get)?,
+        ObjectSubcommands::Put {
+            from_file: Some(path),
+            ..
+        } => manifest(path, Verb::Put)?,
+        ObjectSubcommands::Get { url, .. } => {
+            let url = url.as_deref().unwrap_or_default();
+            // a glob resolves like the collection it is expanded in
+            match object::split_glob(url) {
+                Some((base_url, _)) => vec![one(&base_url, Verb::Get)],
+                None => vec![one(&object::unescape_glob(url), Verb::Get)],
+            }
+        }
+        ObjectSubcommands::Put { url, .. } => {
+            vec![one(url.as_deref().unwrap_or_default(), Verb::Put)]
+        }
+        ObjectSubcommands::Ls { url, .. } | ObjectSubcommands::Stat { url, .. } => {
+            vec![one(url, Verb::Get)]
+        }
+        ObjectSubcommands::Rm { url, .. } => vec![one(url, Verb::Delete)],
+        ObjectSubcommands::Mkdir { url, .. } => vec![one(url, Verb::Mkcol)],
+        // one credential has to cover both urls, so these plan themselves
+        ObjectSubcommands::Cp { .. } | ObjectSubcommands::Mv { .. } => return Ok(None),
+        ObjectSubcommands::Sync { .. } => return Ok(None),
+    }))
+}
+
+fn run_object(cli: &Cli, command: &ObjectSubcommands) -> Result<(), Box<dyn Error>> {
+    // clap drops `requires = "from_file"` when a url is given, since that
+    // conflicts with --from-file
+    if let ObjectSubcommands::Get {
+        from_file: None,
+        parallel,
+        ..
+    }
+    | ObjectSubcommands::Put {
+        from_file: None,
+        parallel,
+        ..
+    } = command
+        && *parallel != 1
+    {
+        return Err(Box::new(error::MyError::Generic(
+            "--parallel only applies to --from-file".into(),
+        )));
+    }
 
-    // get Pelican info
-    let origin = pelican::PelicanInfo::from_url(transfer.url.as_str())?;
+    // get credentials
+    let creds = cli.credentials()?;
+    let report = &cli.reporter();
 
-    // do transfer
-    transfer.execute(&creds, &origin)?;
+    if cli.dry_run
+        && let Some(transfers) = dry_run_transfers(command)?
+    {
+        return batch::dry_run(&creds, &transfers, report);
+    }
 
-    Ok(())
+    match command {
+        ObjectSubcommands::Get {
+            from_file: Some(manifest),
+            parallel,
+            ..
+        } => run_batch(report, &creds, manifest, transfer::Verb::Get, *parallel),
+        ObjectSubcommands::Get {
+            url: Some(url),
+            filename: Some(filename),
+            recursive: true,
+            include,
+            exclude,
+            ..
+        } => object::get_recursive(
+            &creds,
+            url,
+            filename,
+            &object::NameFilter::new(include, exclude)?,
+            report,
+        ),
+        ObjectSubcommands::Get {
+            url: Some(url),
+            filename: Some(filename),
+            ..
+        } if object::split_glob(url).is_some() => object::get_glob(&creds, url, filename, report),
+        ObjectSubcommands::Get {
+            url: Some(url),
+            filename: Some(filename),
+            ..
+        } => run_transfer(
+            report,
+            &creds,
+            &object::unescape_glob(url),
+            filename,
+            transfer::Verb::Get,
+        ),
+        ObjectSubcommands::Put {
+            from_file: Some(manifest),
+            parallel,
+            ..
+        } => run_batch(report, &creds, manifest, transfer::Verb::Put, *parallel),
+        ObjectSubcommands::Put {
+            filename: Some(filename),
+            url: Some(url),
+            recursive: true,
+            include,
+            exclude,
+            symlinks,
+            ..
+        } => object::put_recursive(
+            &creds,
+            filename,
+            url,
+            &object::NameFilter::new(include, exclude)?,
+            *symlinks,
+            report,
+        ),
+        ObjectSubcommands::Put {
+            filename: Some(filename),
+            url: Some(url),
+            ..
+        } => run_transfer(report, &creds, url, filename, transfer::Verb::Put),
+        ObjectSubcommands::Get { .. } | ObjectSubcommands::Put { .. } => Err(Box::new(
+            error::MyError::Generic("a url and a filename are needed without --from-file".into()),
+        )),
+        ObjectSubcommands::Ls {
+            url,
+            long,
+            json,
+            depth,
+            recursive,
+        } => object::ls(
+            &creds,
+            url,
+            *long,
+            *recursive || depth == "infinity",
+            &report.with_json(*json),
+        ),
+        ObjectSubcommands::Stat { url, json } => {
+            object::stat(&creds, url, &report.with_json(*json))
+        }
+        ObjectSubcommands::Rm { url, recursive } => {
+            report.operation(url, "delete", object::rm(&creds, url, *recursive))
+        }
+        ObjectSubcommands::Sync {
+            src,
+            dst,
+            checksum,
+            delete,
+            dry_run,
+        } => sync::sync(
+            &creds,
+            src,
+            dst,
+            &sync::SyncOptions {
+                checksum: *checksum,
+                delete: *delete,
+                dry_run: *dry_run || cli.dry_run,
+                no_local_reads: cli.dry_run,
+            },
+            report,
+        ),
+        ObjectSubcommands::Mkdir { url, parents } => {
+            report.operation(url, "mkcol", object::mkdir(&creds, url, *parents))
+        }
+        ObjectSubcommands::Cp { src, dst, .. } if cli.dry_run => {
+            object::plan_copy(&creds, src, dst, false, report)
+        }
+        ObjectSubcommands::Mv { src, dst, .. } if cli.dry_run => {
+            object::plan_copy(&creds, src, dst, true, report)
+        }
+        ObjectSubcommands::Cp {
+            src,
+            dst,
+            no_clobber,
+        } => report.operation(
+            src,
+            "copy",
+            object::cp(&creds, src, dst, false, !no_clobber),
+        ),
+        ObjectSubcommands::Mv {
+            src,
+            dst,
+            no_clobber,
+        } => report.operation(src, "move", object::cp(&creds, src, dst, true, !no_clobber)),
+    }
 }
 
 fn main() -> ExitCode {
-    match run() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if plugin::is_plugin_invocation(&args) {
+        return plugin::main(&args);
+    }
+
+    let cli = Cli::parse();
+    match run(&cli) {
         Ok(_) => ExitCode::SUCCESS,
         Err(e) => {
             log::info!("Backtrace: {}", Backtrace::force_capture());
             log::error!("Error: {e}");
-            ExitCode::FAILURE
+            // failed before any operation could report itself
+            if cli.output == output::OutputFormat::Json && !output::anything_reported() {
+                let report = output::OperationReport::new(
+                    None,
+                    None,
+                    "",
+                    Err(output::ErrorReport::new(e.as_ref())),
+                );
+                let _ = cli.reporter().json(&report);
+            }
+            match e.downcast_ref::<error::MyError>() {
+                Some(error::MyError::Http {
+                    status: Some(404), ..
+                }) => ExitCode::from(EXIT_NOT_FOUND),
+                Some(error::MyError::Http {
+                    status: Some(401 | 403),
+                    ..
+                }) => ExitCode::from(EXIT_FORBIDDEN),
+                _ => ExitCode::FAILURE,
+            }
         }
     }
 }
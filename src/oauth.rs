@@ -0,0 +1,544 @@
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::config;
+use crate::credentials::Credential;
+use crate::error::MyError;
+
+const WELL_KNOWN: &str = "/.well-known/openid-configuration";
+/// The environment variable with the OAuth2 client secret
+pub(crate) const CLIENT_SECRET_ENV: &str = "DUMB_PELICAN_CLIENT_SECRET";
+
+/// Read a client secret from a file, without its trailing newline.
+pub fn read_secret_file(path: &str) -> Result<String, Box<dyn Error>> {
+    Ok(fs::read_to_string(path)?.trim().to_string())
+}
+
+/// The client secret from the environment, or else the file named by the
+/// `client_secret_file` setting, for tokens of a confidential client.
+pub fn client_secret() -> Result<Option<String>, Box<dyn Error>> {
+    if let Ok(secret) = std::env::var(CLIENT_SECRET_ENV) {
+        return Ok(Some(secret));
+    }
+    match &config::get().client_secret_file {
+        Some(path) => Ok(Some(read_secret_file(&path.value)?)),
+        None => Ok(None),
+    }
+}
+
+pub(crate) fn http_client() -> reqwest::blocking::Client {
+    reqwest::blocking::ClientBuilder::new()
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(Duration::from_secs(config::get().timeout.value))
+        .connect_timeout(Duration::from_secs(config::get().connect_timeout.value))
+        .build()
+        .expect("HTTP Client should build")
+}
+
+/// The parts of an issuer's OpenID configuration we use.
+#[derive(serde::Deserialize, Debug, PartialEq)]
+pub struct OpenIdConfiguration {
+    pub token_endpoint: String,
+    pub device_authorization_endpoint: Option<String>,
+}
+
+/// Read an issuer's `/.well-known/openid-configuration`.
+pub fn discover(
+    client: &reqwest::blocking::Client,
+    issuer: &str,
+) -> Result<OpenIdConfiguration, Box<dyn Error>> {
+    let url = format!("{}{}", issuer.trim_end_matches('/'), WELL_KNOWN);
+    log::info!("reading OpenID configuration {}", url);
+    let response = client.get(&url).send().map_err(|e| MyError::Http {
+        url: url.clone(),
+        status: None,
+        details: format!("Cannot contact the token issuer: {}", e),
+    })?;
+    let status = response.status().as_u16();
+    if status >= 400 {
+        return Err(Box::new(MyError::Http {
+            url,
+            status: Some(status),
+            details: "Error reading the OpenID configuration".into(),
+        }));
+    }
+    Ok(response.json()?)
+}
+
+/// Send a form to a token endpoint, and parse the token response as a
+/// credential. OAuth2 errors come back as `(error, description)`.
+pub(crate) fn token_request(
+    client: &reqwest::blocking::Client,
+    endpoint: &str,
+    form: &[(&str, &str)],
+) -> Result<Result<Credential, (String, String)>, Box<dyn Error>> {
+    let response = client
+        .post(endpoint)
+        .form(form)
+        .send()
+        .map_err(|e| MyError::Http {
+            url: endpoint.to_string(),
+            status: None,
+            details: format!("Cannot contact the token endpoint: {}", e),
+        })?;
+    let status = response.status().as_u16();
+    let body = response.text()?;
+    if status >= 400 {
+        #[derive(serde::Deserialize)]
+        struct OAuthError {
+            error: String,
+            error_description: Option<String>,
+        }
+        return match serde_json::from_str::<OAuthError>(&body) {
+            Ok(e) => Ok(Err((e.error, e.error_description.unwrap_or_default()))),
+            Err(_) => Err(Box::new(MyError::Http {
+                url: endpoint.to_string(),
+                status: Some(status),
+                details: body,
+            })),
+        };
+    }
+    // a relative expires_in counts from now
+    Ok(Ok(Credential::parse(&body)?))
+}
+
+/// Get a new access token with the credential's refresh token, from the
+/// credential's issuer or else the configured one, as the client that got it.
+pub fn refresh(
+    client: &reqwest::blocking::Client,
+    cred: &Credential,
+    oauth_client: Option<&OAuthClient>,
+) -> Result<Credential, Box<dyn Error>> {
+    let refresh_token = cred
+        .refresh_token
+        .as_deref()
+        .ok_or_else(|| MyError::Credentials("credential has no refresh token".into()))?;
+    let issuer = match (&cred.iss, &config::get().issuer) {
+        (Some(iss), _) => iss.clone(),
+        (None, Some(iss)) => iss.value.clone(),
+        (None, None) => {
+            return Err(Box::new(MyError::Credentials(
+                "the issuer of the credential is unknown".into(),
+            )));
+        }
+    };
+    let endpoint = discover(client, &issuer)?.token_endpoint;
+    log::info!("refreshing token with {}", endpoint);
+
+    let mut form = vec![
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token),
+    ];
+    if let Some(oauth_client) = oauth_client {
+        oauth_client.add_to(&mut form);
+    }
+    let mut new = token_request(client, &endpoint, &form)?.map_err(|(error, description)| {
+        MyError::Credentials(format!("token refresh failed: {} {}", error, description))
+    })?;
+
+    // keep what the response leaves out
+    if new.refresh_token.is_none() {
+        new.refresh_token = cred.refresh_token.clone();
+    }
+    if new.scope.is_empty() {
+        new.scope = cred.scope.clone();
+    }
+    if new.iss.is_none() {
+        new.iss = Some(issuer);
+    }
+    if new.aud.is_empty() {
+        new.aud = cred.aud.clone();
+    }
+    new.client_id = cred.client_id.clone();
+    new.source = cred.source.clone();
+    Ok(new)
+}
+
+/// What the device authorization endpoint says to show the user.
+#[derive(serde::Deserialize, Debug, PartialEq)]
+pub struct DeviceAuthorization {
+    pub device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    pub verification_uri_complete: Option<String>,
+    /// Seconds until the codes expire
+    pub expires_in: u64,
+    /// Seconds to wait between polls
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+}
+
+fn default_interval() -> u64 {
+    5
+}
+
+/// An OAuth2 client, as registered with the issuer.
+pub struct OAuthClient<'a> {
+    pub id: &'a str,
+    pub secret: Option<&'a str>,
+}
+
+impl OAuthClient<'_> {
+    fn add_to<'b>(&'b self, form: &mut Vec<(&'b str, &'b str)>) {
+        form.push(("client_id", self.id));
+        if let Some(secret) = self.secret {
+            form.push(("client_secret", secret));
+        }
+    }
+}
+
+/// Get a token with the OAuth2 device authorization grant (RFC 8628):
+/// ask for a device code, let `prompt` show the user where to approve it,
+/// then poll the token endpoint until they do.
+pub fn device_flow(
+    client: &reqwest::blocking::Client,
+    issuer: &str,
+    oauth_client: &OAuthClient,
+    scope: &str,
+    prompt: impl Fn(&DeviceAuthorization),
+) -> Result<Credential, Box<dyn Error>> {
+    let openid = discover(client, issuer)?;
+    let endpoint = openid.device_authorization_endpoint.ok_or_else(|| {
+        MyError::Credentials(format!(
+            "{} does not support the device authorization grant",
+            issuer
+        ))
+    })?;
+
+    let mut form = vec![("scope", scope)];
+    oauth_client.add_to(&mut form);
+    let response = client
+        .post(&endpoint)
+        .form(&form)
+        .send()
+        .map_err(|e| MyError::Http {
+            url: endpoint.clone(),
+            status: None,
+            details: format!("Cannot contact the device authorization endpoint: {}", e),
+        })?;
+    let status = response.status().as_u16();
+    if status >= 400 {
+        return Err(Box::new(MyError::Http {
+            url: endpoint,
+            status: Some(status),
+            details: response.text().unwrap_or_default(),
+        }));
+    }
+    let device: DeviceAuthorization = response.json()?;
+    prompt(&device);
+
+    let deadline = Instant::now() + Duration::from_secs(device.expires_in);
+    let mut interval = device.interval;
+    let mut form = vec![
+        ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+        ("device_code", device.device_code.as_str()),
+    ];
+    oauth_client.add_to(&mut form);
+    loop {
+        match token_request(client, &openid.token_endpoint, &form)? {
+            Ok(mut cred) => {
+                if cred.iss.is_none() {
+                    cred.iss = Some(issuer.to_string());
+                }
+                if cred.scope.is_empty() {
+                    cred.scope = scope.split_whitespace().map(String::from).collect();
+                }
+                cred.client_id = Some(oauth_client.id.to_string());
+                return Ok(cred);
+            }
+            Err((error, _)) if error == "authorization_pending" => {}
+            Err((error, _)) if error == "slow_down" => interval += 5,
+            Err((error, description)) => {
+                return Err(Box::new(MyError::Credentials(format!(
+                    "device authorization failed: {} {}",
+                    error, description
+                ))));
+            }
+        }
+        if Instant::now() >= deadline {
+            return Err(Box::new(MyError::Credentials(
+                "device code expired before it was approved".into(),
+            )));
+        }
+        log::debug!("waiting {}s for approval", interval);
+        std::thread::sleep(Duration::from_secs(interval));
+    }
+}
+
+/// Replace a credential file without readers ever seeing a partial one,
+/// by writing a temporary file next to it and renaming it into place.
+pub fn write_atomically(path: &str, cred: &Credential) -> Result<(), Box<dyn Error>> {
+    let path = Path::new(path);
+    let tmp_path = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        path.file_name().unwrap_or_default().to_string_lossy(),
+        std::process::id()
+    ));
+    // tokens are secrets, so only the owner can read them
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)?;
+    let result = file
+        .write_all(&serde_json::to_vec_pretty(cred)?)
+        .and_then(|_| file.sync_all())
+        .and_then(|_| fs::rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    Ok(result?)
+}
+
+#[cfg(test)]
+mod tests {
+    use httpmock::prelude::*;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::logging::test_logger;
+
+    #[test]
+    fn test_refresh() {
+        test_logger();
+
+        let server = MockServer::start();
+        let well_known = server.mock(|when, then| {
+            when.method(GET)
+                .path("/issuer/.well-known/openid-configuration");
+            then.status(200).json_body(serde_json::json!({
+                "issuer": server.url("/issuer"),
+                "token_endpoint": server.url("/issuer/token"),
+            }));
+        });
+        let token = server.mock(|when, then| {
+            when.method(POST)
+                .path("/issuer/token")
+                .form_urlencoded_tuple("grant_type", "refresh_token")
+                .form_urlencoded_tuple("refresh_token", "refresh")
+                .form_urlencoded_tuple("client_id", "client");
+            then.status(200).json_body(serde_json::json!({
+                "access_token": "new",
+                "token_type": "bearer",
+                "expires_in": 1200,
+            }));
+        });
+
+        let cred = Credential {
+            access_token: "old".into(),
+            scope: vec!["storage.read:/".into()],
+            expires_at: 1.,
+            refresh_token: Some("refresh".into()),
+            iss: Some(server.url("/issuer")),
+            client_id: Some("client".into()),
+            source: Some("/creds/scitokens.use".into()),
+            ..Default::default()
+        };
+        let oauth_client = OAuthClient {
+            id: "client",
+            secret: None,
+        };
+        let new = refresh(&http_client(), &cred, Some(&oauth_client)).unwrap();
+        well_known.assert();
+        token.assert();
+        assert_eq!(new.access_token, "new");
+        assert_eq!(new.refresh_token.as_deref(), Some("refresh"));
+        assert_eq!(new.scope, cred.scope);
+        assert_eq!(new.source, cred.source);
+        assert!(new.expires_at > 1000.);
+
+        let cred = Credential { iss: None, ..cred };
+        assert!(refresh(&http_client(), &cred, Some(&oauth_client)).is_err());
+    }
+
+    #[test]
+    fn test_refresh_client_secret() {
+        test_logger();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/.well-known/openid-configuration");
+            then.status(200).json_body(serde_json::json!({
+                "token_endpoint": server.url("/token"),
+            }));
+        });
+        let token = server.mock(|when, then| {
+            when.method(POST)
+                .path("/token")
+                .form_urlencoded_tuple("client_id", "client")
+                .form_urlencoded_tuple("client_secret", "secret");
+            then.status(200).json_body(serde_json::json!({
+                "access_token": "new",
+                "expires_in": 1200,
+            }));
+        });
+
+        let cred = Credential {
+            access_token: "old".into(),
+            refresh_token: Some("refresh".into()),
+            iss: Some(server.url("")),
+            client_id: Some("client".into()),
+            ..Default::default()
+        };
+        let oauth_client = OAuthClient {
+            id: "client",
+            secret: Some("secret"),
+        };
+        refresh(&http_client(), &cred, Some(&oauth_client)).unwrap();
+        token.assert();
+    }
+
+    #[test]
+    fn test_refresh_error() {
+        test_logger();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/.well-known/openid-configuration");
+            then.status(200).json_body(serde_json::json!({
+                "token_endpoint": server.url("/token"),
+            }));
+        });
+        server.mock(|when, then| {
+            when.method(POST).path("/token");
+            then.status(400).json_body(serde_json::json!({
+                "error": "invalid_grant",
+                "error_description": "refresh token expired",
+            }));
+        });
+
+        let cred = Credential {
+            access_token: "old".into(),
+            refresh_token: Some("refresh".into()),
+            iss: Some(server.url("")),
+            ..Default::default()
+        };
+        let err = refresh(&http_client(), &cred, None).unwrap_err();
+        assert!(err.to_string().contains("invalid_grant"));
+    }
+
+    #[test]
+    fn test_device_flow() {
+        test_logger();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/.well-known/openid-configuration");
+            then.status(200).json_body(serde_json::json!({
+                "token_endpoint": server.url("/token"),
+                "device_authorization_endpoint": server.url("/device"),
+            }));
+        });
+        let device = server.mock(|when, then| {
+            when.method(POST)
+                .path("/device")
+                .form_urlencoded_tuple("client_id", "client")
+                .form_urlencoded_tuple("scope", "storage.read:/ offline_access");
+            then.status(200).json_body(serde_json::json!({
+                "device_code": "device-code",
+                "user_code": "ABCD-EFGH",
+                "verification_uri": "https://issuer/device",
+                "expires_in": 60,
+                "interval": 0,
+            }));
+        });
+        let token = server.mock(|when, then| {
+            when.method(POST)
+                .path("/token")
+                .form_urlencoded_tuple("grant_type", "urn:ietf:params:oauth:grant-type:device_code")
+                .form_urlencoded_tuple("device_code", "device-code");
+            then.status(200).json_body(serde_json::json!({
+                "access_token": "token",
+                "refresh_token": "refresh",
+                "expires_in": 1200,
+            }));
+        });
+
+        let oauth_client = OAuthClient {
+            id: "client",
+            secret: None,
+        };
+        let shown = std::cell::RefCell::new(None);
+        let cred = device_flow(
+            &http_client(),
+            &server.url(""),
+            &oauth_client,
+            "storage.read:/ offline_access",
+            |d| *shown.borrow_mut() = Some(d.user_code.clone()),
+        )
+        .unwrap();
+        device.assert();
+        token.assert();
+        assert_eq!(shown.into_inner().as_deref(), Some("ABCD-EFGH"));
+        assert_eq!(cred.access_token, "token");
+        assert_eq!(cred.refresh_token.as_deref(), Some("refresh"));
+        assert_eq!(cred.iss, Some(server.url("")));
+        assert_eq!(cred.client_id.as_deref(), Some("client"));
+        assert_eq!(cred.scope, vec!["storage.read:/", "offline_access"]);
+    }
+
+    #[test]
+    fn test_device_flow_denied() {
+        test_logger();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/.well-known/openid-configuration");
+            then.status(200).json_body(serde_json::json!({
+                "token_endpoint": server.url("/token"),
+                "device_authorization_endpoint": server.url("/device"),
+            }));
+        });
+        server.mock(|when, then| {
+            when.method(POST).path("/device");
+            then.status(200).json_body(serde_json::json!({
+                "device_code": "device-code",
+                "user_code": "ABCD-EFGH",
+                "verification_uri": "https://issuer/device",
+                "expires_in": 60,
+                "interval": 0,
+            }));
+        });
+        server.mock(|when, then| {
+            when.method(POST).path("/token");
+            then.status(400)
+                .json_body(serde_json::json!({"error": "access_denied"}));
+        });
+
+        let oauth_client = OAuthClient {
+            id: "client",
+            secret: Some("secret"),
+        };
+        let err =
+            device_flow(&http_client(), &server.url(""), &oauth_client, "", |_| {}).unwrap_err();
+        assert!(err.to_string().contains("access_denied"));
+    }
+
+    #[test]
+    fn test_write_atomically() {
+        let tmp_dir = TempDir::new().unwrap();
+        let path = tmp_dir.path().join("scitokens.use");
+        fs::write(&path, "old").unwrap();
+
+        let cred = Credential {
+            access_token: "new".into(),
+            refresh_token: Some("refresh".into()),
+            expires_at: 1234.,
+            ..Default::default()
+        };
+        write_atomically(path.to_str().unwrap(), &cred).unwrap();
+        let read = Credential::parse(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(read, cred);
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert_eq!(fs::read_dir(tmp_dir.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,1193 @@
+use std::collections::{BTreeSet, HashSet};
+use std::error::Error;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+
+use crate::batch;
+use crate::credentials::Credentials;
+use crate::error::MyError;
+use crate::output::Reporter;
+use crate::pelican::PelicanInfo;
+use crate::transfer::{
+    STDIO_FILENAME, Transfer, Verb, build_http_client, get_origin_url, get_origin_urls,
+};
+use crate::webdav;
+
+fn not_found(url: &str) -> Box<dyn Error> {
+    Box::new(MyError::Http {
+        url: url.to_string(),
+        status: Some(404),
+        details: "Object not found".into(),
+    })
+}
+
+fn http_status(e: &(dyn Error + 'static)) -> Option<u16> {
+    match e.downcast_ref::<MyError>() {
+        Some(MyError::Http { status, .. }) => *status,
+        _ => None,
+    }
+}
+
+/// The url of the collection containing `url`, if it is inside the namespace.
+fn parent_url(url: &str, origin: &PelicanInfo) -> Option<String> {
+    let (parent, _) = url.trim_end_matches('/').rsplit_once('/')?;
+    if parent.len() > origin.get_osdf_prefix().len() {
+        Some(parent.to_string())
+    } else {
+        None
+    }
+}
+
+/// Include and exclude globs, matched against paths relative to the
+/// top of a recursive transfer.
+pub struct NameFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+fn build_globset(patterns: &[String]) -> Result<GlobSet, Box<dyn Error>> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns.iter() {
+        builder.add(Glob::new(pattern)?);
+    }
+    Ok(builder.build()?)
+}
+
+impl NameFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self, Box<dyn Error>> {
+        Ok(NameFilter {
+            include: if include.is_empty() {
+                None
+            } else {
+                Some(build_globset(include)?)
+            },
+            exclude: build_globset(exclude)?,
+        })
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        if self.exclude.is_match(name) {
+            return false;
+        }
+        match &self.include {
+            Some(include) => include.is_match(name),
+            None => true,
+        }
+    }
+}
+
+/// Join a remote relative name onto a local directory, refusing anything
+/// that could land outside of it.
+pub fn safe_local_path(base: &Path, name: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let mut ret = base.to_path_buf();
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(part) => ret.push(part),
+            Component::CurDir => {}
+            _ => {
+                return Err(Box::new(MyError::Transfer(format!(
+                    "refusing unsafe remote name {:?}",
+                    name
+                ))));
+            }
+        }
+    }
+    if ret == base {
+        return Err(Box::new(MyError::Transfer(format!(
+            "refusing empty remote name {:?}",
+            name
+        ))));
+    }
+    Ok(ret)
+}
+
+/// Download every file under a collection into `dir`, recreating the tree.
+pub fn get_recursive(
+    creds: &Credentials,
+    url: &str,
+    dir: &str,
+    filter: &NameFilter,
+    report: &Reporter,
+) -> Result<(), Box<dyn Error>> {
+    let origin = PelicanInfo::from_url(url)?;
+    let cred = creds.get_cred_for_url(url, &["storage.read"], &origin)?;
+    let http_client = build_http_client(cred)?;
+    get_tree(url, Path::new(dir), filter, report, &origin, &http_client)
+}
+
+fn get_tree(
+    url: &str,
+    base: &Path,
+    filter: &NameFilter,
+    report: &Reporter,
+    origin: &PelicanInfo,
+    http_client: &reqwest::blocking::Client,
+) -> Result<(), Box<dyn Error>> {
+    let origin_url = get_origin_url(url, origin)?;
+    let entries = webdav::list(http_client, &origin_url, true)?;
+    let names: Vec<&str> = entries
+        .iter()
+        .filter(|entry| !entry.is_collection && filter.matches(&entry.name))
+        .map(|entry| entry.name.as_str())
+        .collect();
+    get_files(url, &names, base, report, origin, http_client)
+}
+
+/// Download the files with these names relative to the collection `url`
+/// into `base`, recreating any subdirectories.
+fn get_files(
+    url: &str,
+    names: &[&str],
+    base: &Path,
+    report: &Reporter,
+    origin: &PelicanInfo,
+    http_client: &reqwest::blocking::Client,
+) -> Result<(), Box<dyn Error>> {
+    let mut failed = 0;
+    for name in names.iter() {
+        let entry_url = format!(
+            "{}/{}",
+            url.trim_end_matches('/'),
+            webdav::encode_path(name)
+        );
+        let path = base.join(name).to_string_lossy().into_owned();
+        let result = safe_local_path(base, name).and_then(|local_path| {
+            if let Some(parent) = local_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let transfer = Transfer::new(
+                entry_url.clone(),
+                local_path.to_string_lossy().into_owned(),
+                Verb::Get,
+            );
+            transfer.execute_with_client(origin, http_client)
+        });
+        report.transfer(&entry_url, &path, "get", &result)?;
+        if let Err(e) = result {
+            log::error!("Error getting {}: {}", name, e);
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        return Err(Box::new(MyError::Transfer(format!(
+            "{} of {} files failed",
+            failed,
+            names.len()
+        ))));
+    }
+    Ok(())
+}
+
+/// Whether a path component is a glob pattern, with a `\` escaping the
+/// character after it.
+fn is_glob(component: &str) -> bool {
+    let mut chars = component.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '*' | '?' | '[' => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Drop the `\` escapes from a url, for names with `*`, `?` or `[` in them.
+pub fn unescape_glob(url: &str) -> String {
+    let mut ret = String::with_capacity(url.len());
+    let mut chars = url.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => ret.extend(chars.next()),
+            _ => ret.push(c),
+        }
+    }
+    ret
+}
+
+/// Split a url with glob patterns in its path into the deepest collection
+/// without any, and the pattern for names relative to it. Urls without
+/// patterns give None.
+pub fn split_glob(url: &str) -> Option<(String, String)> {
+    let path_start = url.find("://").map(|n| n + 3).unwrap_or(0);
+    let path_start = path_start + url[path_start..].find('/')?;
+    let components: Vec<&str> = url[path_start + 1..].split('/').collect();
+    let first_glob = components.iter().position(|c| is_glob(c))?;
+    let base = format!(
+        "{}/{}",
+        &url[..path_start],
+        unescape_glob(&components[..first_glob].join("/"))
+    );
+    Some((
+        base.trim_end_matches('/').to_string(),
+        components[first_glob..].join("/"),
+    ))
+}
+
+/// Download every file matching a url with glob patterns (`*`, `?`, `[...]`,
+/// and `**` for any number of collections) into `dir`.
+pub fn get_glob(
+    creds: &Credentials,
+    url: &str,
+    dir: &str,
+    report: &Reporter,
+) -> Result<(), Box<dyn Error>> {
+    let (base_url, pattern) =
+        split_glob(url).ok_or_else(|| MyError::Transfer(format!("{} has no glob pattern", url)))?;
+    if dir == STDIO_FILENAME {
+        return Err(Box::new(MyError::Transfer(
+            "a glob can match many files, so it can't be written to stdout".into(),
+        )));
+    }
+    let origin = PelicanInfo::from_url(&base_url)?;
+    let cred = creds.get_cred_for_url(&base_url, &["storage.read"], &origin)?;
+    let http_client = build_http_client(cred)?;
+    get_matching(
+        &base_url,
+        &pattern,
+        Path::new(dir),
+        report,
+        &origin,
+        &http_client,
+    )
+}
+
+fn get_matching(
+    base_url: &str,
+    pattern: &str,
+    base: &Path,
+    report: &Reporter,
+    origin: &PelicanInfo,
+    http_client: &reqwest::blocking::Client,
+) -> Result<(), Box<dyn Error>> {
+    let matcher = GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()?
+        .compile_matcher();
+    // only list the whole tree if the pattern can match below the top
+    let recursive = pattern.contains('/') || pattern.contains("**");
+    let origin_url = get_origin_url(base_url, origin)?;
+    let entries = webdav::list(http_client, &origin_url, recursive)?;
+    let names: Vec<&str> = entries
+        .iter()
+        .filter(|entry| !entry.is_collection && matcher.is_match(&entry.name))
+        .map(|entry| entry.name.as_str())
+        .collect();
+    if names.is_empty() {
+        return Err(not_found(&format!("{}/{}", base_url, pattern)));
+    }
+    log::info!("{} files match {}", names.len(), pattern);
+    get_files(base_url, &names, base, report, origin, http_client)
+}
+
+/// What to do with symlinks when uploading a directory.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum SymlinkPolicy {
+    /// Upload what the link points to
+    Follow,
+    /// Leave links out
+    Skip,
+    /// Fail the upload
+    Error,
+}
+
+/// Find the files under `base`, as `/`-separated relative names.
+pub fn walk_local(
+    base: &Path,
+    filter: &NameFilter,
+    symlinks: SymlinkPolicy,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut ret = Vec::new();
+    // guards against symlink loops when following links
+    let mut visited = HashSet::new();
+    visited.insert(fs::canonicalize(base)?);
+
+    let mut pending = vec![(base.to_path_buf(), String::new())];
+    while let Some((dir, prefix)) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = match entry.file_name().to_str() {
+                Some(n) => format!("{}{}", prefix, n),
+                None => {
+                    return Err(Box::new(MyError::Transfer(format!(
+                        "file name is not valid unicode: {:?}",
+                        entry.path()
+                    ))));
+                }
+            };
+            let mut file_type = entry.file_type()?;
+            if file_type.is_symlink() {
+                match symlinks {
+                    SymlinkPolicy::Skip => {
+                        log::info!("skipping symlink {}", name);
+                        continue;
+                    }
+                    SymlinkPolicy::Error => {
+                        return Err(Box::new(MyError::Transfer(format!(
+                            "refusing to upload symlink {:?}",
+                            entry.path()
+                        ))));
+                    }
+                    SymlinkPolicy::Follow => match fs::metadata(entry.path()) {
+                        Ok(metadata) => file_type = metadata.file_type(),
+                        // a dangling link fails on its own when it is opened
+                        Err(e) => log::warn!("cannot follow symlink {}: {}", name, e),
+                    },
+                }
+            }
+            if file_type.is_dir() {
+                if visited.insert(fs::canonicalize(entry.path())?) {
+                    pending.push((entry.path(), format!("{}/", name)));
+                } else {
+                    log::warn!("skipping already visited directory {}", name);
+                }
+            } else if filter.matches(&name) {
+                ret.push(name);
+            }
+        }
+    }
+    ret.sort();
+    Ok(ret)
+}
+
+/// Upload every file under `dir`, creating collections as needed.
+pub fn put_recursive(
+    creds: &Credentials,
+    dir: &str,
+    url: &str,
+    filter: &NameFilter,
+    symlinks: SymlinkPolicy,
+    report: &Reporter,
+) -> Result<(), Box<dyn Error>> {
+    let origin = PelicanInfo::from_url(url)?;
+    let cred = creds.get_cred_for_url(url, &["storage.create", "storage.modify"], &origin)?;
+    let http_client = build_http_client(cred)?;
+    put_tree(
+        Path::new(dir),
+        url,
+        filter,
+        symlinks,
+        report,
+        &origin,
+        &http_client,
+    )
+}
+
+fn put_tree(
+    base: &Path,
+    url: &str,
+    filter: &NameFilter,
+    symlinks: SymlinkPolicy,
+    report: &Reporter,
+    origin: &PelicanInfo,
+    http_client: &reqwest::blocking::Client,
+) -> Result<(), Box<dyn Error>> {
+    let files = walk_local(base, filter, symlinks)?;
+    let url = url.trim_end_matches('/');
+    let child_url = |name: &str| format!("{}/{}", url, webdav::encode_path(name));
+    make_collections_for(url, &files, origin, http_client)?;
+
+    let mut failed = 0;
+    for name in files.iter() {
+        let transfer = Transfer::new(
+            child_url(name),
+            base.join(name).to_string_lossy().into_owned(),
+            Verb::Put,
+        );
+        let result = transfer.execute_with_client(origin, http_client);
+        report.transfer(
+            &transfer.url,
+            &base.join(name).to_string_lossy(),
+            "put",
+            &result,
+        )?;
+        if let Err(e) = result {
+            log::error!("Error putting {}: {}", name, e);
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        return Err(Box::new(MyError::Transfer(format!(
+            "{} of {} files failed",
+            failed,
+            files.len()
+        ))));
+    }
+    Ok(())
+}
+
+/// The contents of a collection, or a file as just itself, like `ls` does.
+fn list_entries(
+    http_client: &reqwest::blocking::Client,
+    origin_url: &str,
+    recursive: bool,
+) -> Result<Vec<webdav::DavEntry>, Box<dyn Error>> {
+    let mut entries = webdav::list(http_client, origin_url, recursive)?;
+    if entries.is_empty() {
+        for mut entry in webdav::propfind(http_client, origin_url, "0")? {
+            if entry.name.is_empty() && !entry.is_collection {
+                let name = origin_url.trim_end_matches('/').rsplit('/').next();
+                entry.name = webdav::percent_decode(name.unwrap_or_default());
+                entries.push(entry);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+pub fn ls(
+    creds: &Credentials,
+    url: &str,
+    long: bool,
+    recursive: bool,
+    report: &Reporter,
+) -> Result<(), Box<dyn Error>> {
+    let origin = PelicanInfo::from_url(url)?;
+    let cred = creds.get_cred_for_url(url, &["storage.read"], &origin)?;
+    let http_client = build_http_client(cred)?;
+    let origin_url = get_origin_url(url, &origin)?;
+
+    let entries = list_entries(&http_client, &origin_url, recursive)?;
+    if report.is_json() {
+        report.json(&entries)?;
+    } else if long {
+        for entry in entries.iter() {
+            println!(
+                "{} {:>14} {:<29} {}",
+                if entry.is_collection { 'd' } else { '-' },
+                entry.size.map_or("-".into(), |s| s.to_string()),
+                entry.mtime.as_deref().unwrap_or("-"),
+                entry.name
+            );
+        }
+    } else {
+        for entry in entries.iter() {
+            if entry.is_collection {
+                println!("{}/", entry.name);
+            } else {
+                println!("{}", entry.name);
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn stat(creds: &Credentials, url: &str, report: &Reporter) -> Result<(), Box<dyn Error>> {
+    let origin = PelicanInfo::from_url(url)?;
+    let cred = creds.get_cred_for_url(url, &["storage.read"], &origin)?;
+    let http_client = build_http_client(cred)?;
+    let origin_url = get_origin_url(url, &origin)?;
+
+    let mut stat = webdav::stat(&http_client, &origin_url)?;
+    stat.url = url.to_string();
+    if report.is_json() {
+        report.json(&stat)?;
+    } else {
+        println!("url: {}", stat.url);
+        println!("exists: {}", stat.exists);
+        if stat.exists {
+            println!(
+                "type: {}",
+                if stat.is_collection {
+                    "collection"
+                } else {
+                    "file"
+                }
+            );
+            if let Some(size) = stat.size {
+                println!("size: {}", size);
+            }
+            if let Some(mtime) = &stat.mtime {
+                println!("mtime: {}", mtime);
+            }
+            if let Some(etag) = &stat.etag {
+                println!("etag: {}", etag);
+            }
+            for (alg, value) in stat.checksums.iter() {
+                println!("checksum {}: {}", alg, value);
+            }
+        }
+    }
+
+    if !stat.exists {
+        return Err(not_found(&origin_url));
+    }
+    Ok(())
+}
+
+pub fn rm(creds: &Credentials, url: &str, recursive: bool) -> Result<(), Box<dyn Error>> {
+    let origin = PelicanInfo::from_url(url)?;
+    remove(creds, url, recursive, &origin)
+}
+
+fn remove(
+    creds: &Credentials,
+    url: &str,
+    recursive: bool,
+    origin: &PelicanInfo,
+) -> Result<(), Box<dyn Error>> {
+    let transfer = Transfer::new(url.to_string(), String::new(), Verb::Delete);
+    if !recursive {
+        // a storage.modify token can delete, but not look first
+        let cred = creds.get_correct_cred(&transfer, origin)?;
+        let http_client = build_http_client(cred)?;
+        log::info!("removing {}", url);
+        return match transfer.execute_with_client(origin, &http_client) {
+            Ok(_) => Ok(()),
+            Err(e) if http_status(e.as_ref()) == Some(404) => Err(not_found(url)),
+            Err(e) => Err(e),
+        };
+    }
+
+    // listing the collection needs read rights too
+    let cred = creds.get_cred_for_urls(
+        &[(url, &["storage.read"]), (url, &["storage.modify"])],
+        origin,
+    )?;
+    let http_client = build_http_client(cred)?;
+    let origin_url = get_origin_url(url, origin)?;
+    let stat = webdav::stat(&http_client, &origin_url)?;
+    if !stat.exists {
+        return Err(not_found(url));
+    }
+    if stat.is_collection {
+        // reverse name order puts children before their parents
+        let entries = webdav::list(&http_client, &origin_url, true)?;
+        for entry in entries.iter().rev() {
+            let entry_url = format!(
+                "{}/{}",
+                url.trim_end_matches('/'),
+                webdav::encode_path(&entry.name)
+            );
+            log::info!("removing {}", entry_url);
+            Transfer::new(entry_url, String::new(), Verb::Delete)
+                .execute_with_client(origin, &http_client)?;
+        }
+    }
+    log::info!("removing {}", url);
+    transfer.execute_with_client(origin, &http_client)?;
+    Ok(())
+}
+
+/// The scopes a server-side copy or move needs, as one credential must hold
+/// them all: read on the source, plus modify there to remove it for a move,
+/// and create or modify on the destination.
+pub(crate) fn cp_requirements<'a>(
+    src: &'a str,
+    dst: &'a str,
+    is_move: bool,
+) -> Vec<(&'a str, &'static [&'static str])> {
+    let mut ret: Vec<(&str, &[&str])> = vec![(src, &["storage.read"])];
+    if is_move {
+        ret.push((src, &["storage.modify"]));
+    }
+    ret.push((dst, &["storage.create", "storage.modify"]));
+    ret
+}
+
+/// Server-side copies can't leave the namespace, and so the origin, of the source.
+pub(crate) fn check_same_namespace(dst: &str, origin: &PelicanInfo) -> Result<(), Box<dyn Error>> {
+    match dst.strip_prefix(origin.get_osdf_prefix()) {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => Ok(()),
+        _ => Err(Box::new(MyError::Transfer(format!(
+            "destination must be in the same namespace as the source ({})",
+            origin.get_osdf_prefix()
+        )))),
+    }
+}
+
+/// Server-side copy or move within one namespace.
+pub fn cp(
+    creds: &Credentials,
+    src: &str,
+    dst: &str,
+    is_move: bool,
+    overwrite: bool,
+) -> Result<(), Box<dyn Error>> {
+    let origin = PelicanInfo::from_url(src)?;
+    copy(creds, src, dst, is_move, overwrite, &origin)
+}
+
+/// Print where a copy or move would go, with the one credential it would use.
+pub fn plan_copy(
+    creds: &Credentials,
+    src: &str,
+    dst: &str,
+    is_move: bool,
+    report: &Reporter,
+) -> Result<(), Box<dyn Error>> {
+    let origin = PelicanInfo::from_url(src)?;
+    check_same_namespace(dst, &origin)?;
+    let cred = creds.get_cred_for_urls(&cp_requirements(src, dst, is_move), &origin)?;
+    let src_verb = if is_move { Verb::Delete } else { Verb::Get };
+    for (url, verb) in [(src, src_verb), (dst, Verb::Put)] {
+        let plan =
+            Transfer::new(url.to_string(), String::new(), verb).plan_with_cred(cred, &origin)?;
+        batch::print_plan(&plan, report)?;
+    }
+    Ok(())
+}
+
+fn copy(
+    creds: &Credentials,
+    src: &str,
+    dst: &str,
+    is_move: bool,
+    overwrite: bool,
+    origin: &PelicanInfo,
+) -> Result<(), Box<dyn Error>> {
+    check_same_namespace(dst, origin)?;
+    let cred = creds.get_cred_for_urls(&cp_requirements(src, dst, is_move), origin)?;
+    let http_client = build_http_client(cred)?;
+
+    let origin_urls = get_origin_urls(&[src, dst], origin)?;
+    webdav::copy(
+        &http_client,
+        &origin_urls[0],
+        &origin_urls[1],
+        is_move,
+        overwrite,
+    )
+}
+
+fn make_collection(
+    url: &str,
+    parents: bool,
+    origin: &PelicanInfo,
+    http_client: &reqwest::blocking::Client,
+) -> Result<(), Box<dyn Error>> {
+    let transfer = Transfer::new(url.to_string(), String::new(), Verb::Mkcol);
+    match transfer.execute_with_client(origin, http_client) {
+        Ok(_) => Ok(()),
+        Err(e) if parents => match http_status(e.as_ref()) {
+            // already exists
+            Some(405) => Ok(()),
+            // parent is missing
+            Some(409) => match parent_url(url, origin) {
+                Some(parent) => {
+                    make_collection(&parent, parents, origin, http_client)?;
+                    transfer.execute_with_client(origin, http_client)?;
+                    Ok(())
+                }
+                None => Err(e),
+            },
+            _ => Err(e),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// Create `url` and the collections that will hold the relative `files`
+/// under it, parents first.
+pub fn make_collections_for(
+    url: &str,
+    files: &[String],
+    origin: &PelicanInfo,
+    http_client: &reqwest::blocking::Client,
+) -> Result<(), Box<dyn Error>> {
+    let mut dirs = BTreeSet::new();
+    for name in files.iter() {
+        let mut parent = name.as_str();
+        while let Some((p, _)) = parent.rsplit_once('/') {
+            dirs.insert(p.to_string());
+            parent = p;
+        }
+    }
+    make_collection(url, true, origin, http_client)?;
+    for dir in dirs.iter() {
+        make_collection(
+            &format!("{}/{}", url, webdav::encode_path(dir)),
+            true,
+            origin,
+            http_client,
+        )?;
+    }
+    Ok(())
+}
+
+pub fn mkdir(creds: &Credentials, url: &str, parents: bool) -> Result<(), Box<dyn Error>> {
+    let origin = PelicanInfo::from_url(url)?;
+    let transfer = Transfer::new(url.to_string(), String::new(), Verb::Mkcol);
+    let cred = creds.get_correct_cred(&transfer, &origin)?;
+    let http_client = build_http_client(cred)?;
+    make_collection(url, parents, &origin, &http_client)
+}
+
+#[cfg(test)]
+mod tests {
+    use httpmock::prelude::*;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::logging::{test_cred, test_logger};
+
+    #[test]
+    fn test_list_entries_of_file() {
+        test_logger();
+
+        let server = MockServer::start();
+        let body = r#"<?xml version="1.0"?><D:multistatus xmlns:D="DAV:"><D:response><D:href>/ns/a%20file.txt</D:href><D:propstat><D:prop><D:resourcetype/><D:getcontentlength>5</D:getcontentlength></D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response></D:multistatus>"#;
+        let listing = server.mock(|when, then| {
+            when.is_true(|req| req.method_str() == "PROPFIND")
+                .path("/ns/a%20file.txt");
+            then.status(207).body(body);
+        });
+
+        let client = reqwest::blocking::Client::new();
+        let entries = list_entries(&client, &server.url("/ns/a%20file.txt"), false).unwrap();
+        listing.assert_calls(2);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "a file.txt");
+        assert_eq!(entries[0].size, Some(5));
+    }
+
+    #[test]
+    fn test_remove_with_modify_token() {
+        test_logger();
+
+        let server = MockServer::start();
+        let delete = server.mock(|when, then| {
+            when.method(DELETE).path("/ns/file");
+            then.status(204);
+        });
+        let missing = server.mock(|when, then| {
+            when.method(DELETE).path("/ns/missing");
+            then.status(404);
+        });
+        let look = server.mock(|when, then| {
+            when.is_true(|req| req.method_str() != "DELETE");
+            then.status(403);
+        });
+
+        let info = PelicanInfo {
+            origins: vec![server.url("/ns")],
+            osdf_prefix: "osdf:///ns".into(),
+        };
+        let creds = Credentials::new(vec![test_cred(&["storage.modify:/"])]);
+
+        remove(&creds, "osdf:///ns/file", false, &info).unwrap();
+        delete.assert();
+        let err = remove(&creds, "osdf:///ns/missing", false, &info).unwrap_err();
+        assert_eq!(http_status(err.as_ref()), Some(404));
+        missing.assert();
+        look.assert_calls(0);
+
+        // a recursive remove has to list, so it needs storage.read too
+        assert!(remove(&creds, "osdf:///ns/dir", true, &info).is_err());
+        look.assert_calls(0);
+    }
+
+    #[test]
+    fn test_copy_scopes_and_namespace() {
+        test_logger();
+
+        let server = MockServer::start();
+        let moved = server.mock(|when, then| {
+            when.is_true(|req| req.method_str() == "MOVE").path("/ns/a");
+            then.status(201);
+        });
+
+        let info = PelicanInfo {
+            origins: vec![server.url("/ns")],
+            osdf_prefix: "osdf:///ns".into(),
+        };
+
+        // a move reads the source, then removes it
+        let modify_only = Credentials::new(vec![test_cred(&["storage.modify:/"])]);
+        assert!(
+            copy(
+                &modify_only,
+                "osdf:///ns/a",
+                "osdf:///ns/b",
+                true,
+                true,
+                &info
+            )
+            .is_err()
+        );
+        let read_modify =
+            Credentials::new(vec![test_cred(&["storage.read:/", "storage.modify:/"])]);
+        copy(
+            &read_modify,
+            "osdf:///ns/a",
+            "osdf:///ns/b",
+            true,
+            true,
+            &info,
+        )
+        .unwrap();
+        moved.assert();
+
+        // other namespaces are refused before asking the origin
+        for dst in ["osdf:///other/b", "osdf:///nsX/b"] {
+            let err = copy(&read_modify, "osdf:///ns/a", dst, false, true, &info).unwrap_err();
+            assert!(err.to_string().contains("same namespace"));
+        }
+        moved.assert_calls(1);
+    }
+
+    #[test]
+    fn test_make_collection_parents() {
+        test_logger();
+
+        let server = MockServer::start();
+        let leaf = server.mock(|when, then| {
+            when.is_true(|req| req.method_str() == "MKCOL")
+                .path("/ns/a/b");
+            then.status(409);
+        });
+        let parent = server.mock(|when, then| {
+            when.is_true(|req| req.method_str() == "MKCOL")
+                .path("/ns/a");
+            then.status(201);
+        });
+
+        let info = PelicanInfo {
+            origins: vec![server.url("/ns")],
+            osdf_prefix: "osdf:///ns".into(),
+        };
+        let cred = test_cred(&["storage.create:/"]);
+        let client = build_http_client(&cred).unwrap();
+
+        assert!(make_collection("osdf:///ns/a/b", false, &info, &client).is_err());
+        parent.assert_calls(0);
+
+        // the mocked leaf never stops returning 409, so the second
+        // attempt fails too, but only after the parent was created
+        assert!(make_collection("osdf:///ns/a/b", true, &info, &client).is_err());
+        parent.assert_calls(1);
+        leaf.assert_calls(3);
+    }
+
+    #[test]
+    fn test_name_filter() {
+        let filter = NameFilter::new(&["*.i3.zst".into()], &["**/bad/**".into()]).unwrap();
+        assert!(filter.matches("run1.i3.zst"));
+        assert!(filter.matches("sub/run2.i3.zst"));
+        assert!(!filter.matches("sub/bad/run3.i3.zst"));
+        assert!(!filter.matches("run1.log"));
+
+        let filter = NameFilter::new(&[], &[]).unwrap();
+        assert!(filter.matches("anything"));
+    }
+
+    #[test]
+    fn test_safe_local_path() {
+        let base = Path::new("/tmp/out");
+        assert_eq!(
+            safe_local_path(base, "a/./b.txt").unwrap(),
+            PathBuf::from("/tmp/out/a/b.txt")
+        );
+        assert!(safe_local_path(base, "../b.txt").is_err());
+        assert!(safe_local_path(base, "a/../../b.txt").is_err());
+        assert!(safe_local_path(base, "/etc/passwd").is_err());
+        assert!(safe_local_path(base, "").is_err());
+    }
+
+    #[test]
+    fn test_get_recursive() {
+        test_logger();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.is_true(|req| req.method_str() == "PROPFIND")
+                .path("/ns/dir");
+            then.status(207).body(
+                r#"<?xml version="1.0"?><D:multistatus xmlns:D="DAV:">
+<D:response><D:href>/ns/dir/</D:href><D:propstat><D:prop><D:resourcetype><D:collection/></D:resourcetype></D:prop></D:propstat></D:response>
+<D:response><D:href>/ns/dir/a.txt</D:href><D:propstat><D:prop><D:resourcetype/></D:prop></D:propstat></D:response>
+<D:response><D:href>/ns/dir/skip.log</D:href><D:propstat><D:prop><D:resourcetype/></D:prop></D:propstat></D:response>
+<D:response><D:href>/ns/dir/sub/</D:href><D:propstat><D:prop><D:resourcetype><D:collection/></D:resourcetype></D:prop></D:propstat></D:response>
+</D:multistatus>"#,
+            );
+        });
+        server.mock(|when, then| {
+            when.is_true(|req| req.method_str() == "PROPFIND")
+                .path("/ns/dir/sub");
+            then.status(207).body(
+                r#"<?xml version="1.0"?><D:multistatus xmlns:D="DAV:">
+<D:response><D:href>/ns/dir/sub/</D:href><D:propstat><D:prop><D:resourcetype><D:collection/></D:resourcetype></D:prop></D:propstat></D:response>
+<D:response><D:href>/ns/dir/sub/b.txt</D:href><D:propstat><D:prop><D:resourcetype/></D:prop></D:propstat></D:response>
+</D:multistatus>"#,
+            );
+        });
+        let a = server.mock(|when, then| {
+            when.method(GET).path("/ns/dir/a.txt");
+            then.status(200).body("a");
+        });
+        let b = server.mock(|when, then| {
+            when.method(GET).path("/ns/dir/sub/b.txt");
+            then.status(200).body("b");
+        });
+
+        let creds = Credentials::new(vec![test_cred(&["storage.read:/"])]);
+        let info = PelicanInfo {
+            origins: vec![server.url("/ns")],
+            osdf_prefix: "osdf:///ns".into(),
+        };
+        let cred = creds
+            .get_cred_for_url("osdf:///ns/dir", &["storage.read"], &info)
+            .unwrap();
+        let client = build_http_client(cred).unwrap();
+
+        let tmp_dir = TempDir::new().unwrap();
+        let filter = NameFilter::new(&[], &["*.log".into()]).unwrap();
+        get_tree(
+            "osdf:///ns/dir",
+            tmp_dir.path(),
+            &filter,
+            &Reporter::default(),
+            &info,
+            &client,
+        )
+        .unwrap();
+
+        a.assert();
+        b.assert();
+        assert_eq!(
+            std::fs::read_to_string(tmp_dir.path().join("a.txt")).unwrap(),
+            "a"
+        );
+        assert_eq!(
+            std::fs::read_to_string(tmp_dir.path().join("sub/b.txt")).unwrap(),
+            "b"
+        );
+        assert!(!tmp_dir.path().join("skip.log").exists());
+    }
+
+    #[test]
+    fn test_split_glob() {
+        assert_eq!(
+            split_glob("osdf:///icecube/wipac/run123/*.i3.zst"),
+            Some(("osdf:///icecube/wipac/run123".into(), "*.i3.zst".into()))
+        );
+        assert_eq!(
+            split_glob("pelican://fed.example/ns/run?/**/[ab].txt"),
+            Some(("pelican://fed.example/ns".into(), "run?/**/[ab].txt".into()))
+        );
+        assert_eq!(split_glob("osdf:///icecube/wipac/file.txt"), None);
+
+        // escaped names are literal
+        let url = r"osdf:///icecube/run\[1\].dat";
+        assert_eq!(split_glob(url), None);
+        assert_eq!(unescape_glob(url), "osdf:///icecube/run[1].dat");
+        assert_eq!(
+            split_glob(r"osdf:///icecube/run\[1\]/*.dat"),
+            Some(("osdf:///icecube/run[1]".into(), "*.dat".into()))
+        );
+    }
+
+    #[test]
+    fn test_get_matching() {
+        test_logger();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.is_true(|req| req.method_str() == "PROPFIND")
+                .path("/ns/run");
+            then.status(207).body(
+                r#"<?xml version="1.0"?><D:multistatus xmlns:D="DAV:">
+<D:response><D:href>/ns/run/</D:href><D:propstat><D:prop><D:resourcetype><D:collection/></D:resourcetype></D:prop></D:propstat></D:response>
+<D:response><D:href>/ns/run/a.i3.zst</D:href><D:propstat><D:prop><D:resourcetype/></D:prop></D:propstat></D:response>
+<D:response><D:href>/ns/run/b.log</D:href><D:propstat><D:prop><D:resourcetype/></D:prop></D:propstat></D:response>
+<D:response><D:href>/ns/run/sub/</D:href><D:propstat><D:prop><D:resourcetype><D:collection/></D:resourcetype></D:prop></D:propstat></D:response>
+</D:multistatus>"#,
+            );
+        });
+        let sub = server.mock(|when, then| {
+            when.is_true(|req| req.method_str() == "PROPFIND")
+                .path("/ns/run/sub");
+            then.status(207).body(
+                r#"<?xml version="1.0"?><D:multistatus xmlns:D="DAV:">
+<D:response><D:href>/ns/run/sub/</D:href><D:propstat><D:prop><D:resourcetype><D:collection/></D:resourcetype></D:prop></D:propstat></D:response>
+<D:response><D:href>/ns/run/sub/c.i3.zst</D:href><D:propstat><D:prop><D:resourcetype/></D:prop></D:propstat></D:response>
+</D:multistatus>"#,
+            );
+        });
+        let a = server.mock(|when, then| {
+            when.method(GET).path("/ns/run/a.i3.zst");
+            then.status(200).body("a");
+        });
+        let c = server.mock(|when, then| {
+            when.method(GET).path("/ns/run/sub/c.i3.zst");
+            then.status(200).body("c");
+        });
+
+        let info = PelicanInfo {
+            origins: vec![server.url("/ns")],
+            osdf_prefix: "osdf:///ns".into(),
+        };
+        let cred = test_cred(&["storage.read:/"]);
+        let client = build_http_client(&cred).unwrap();
+        let tmp_dir = TempDir::new().unwrap();
+        let report = Reporter::default();
+
+        // `*` stays in one collection
+        get_matching(
+            "osdf:///ns/run",
+            "*.i3.zst",
+            tmp_dir.path(),
+            &report,
+            &info,
+            &client,
+        )
+        .unwrap();
+        a.assert_calls(1);
+        sub.assert_calls(0);
+        c.assert_calls(0);
+        assert_eq!(
+            fs::read_to_string(tmp_dir.path().join("a.i3.zst")).unwrap(),
+            "a"
+        );
+        assert!(!tmp_dir.path().join("b.log").exists());
+
+        // `**` matches in any collection
+        get_matching(
+            "osdf:///ns/run",
+            "**/*.i3.zst",
+            tmp_dir.path(),
+            &report,
+            &info,
+            &client,
+        )
+        .unwrap();
+        a.assert_calls(2);
+        c.assert_calls(1);
+        assert_eq!(
+            fs::read_to_string(tmp_dir.path().join("sub/c.i3.zst")).unwrap(),
+            "c"
+        );
+
+        let err = get_matching(
+            "osdf:///ns/run",
+            "*.txt",
+            tmp_dir.path(),
+            &report,
+            &info,
+            &client,
+        )
+        .unwrap_err();
+        assert_eq!(http_status(err.as_ref()), Some(404));
+    }
+
+    #[test]
+    fn test_walk_local_symlinks() {
+        let tmp_dir = TempDir::new().unwrap();
+        let base = tmp_dir.path();
+        fs::create_dir_all(base.join("sub")).unwrap();
+        fs::write(base.join("a.txt"), "a").unwrap();
+        fs::write(base.join("sub/b.txt"), "b").unwrap();
+        fs::write(base.join("sub/c.log"), "c").unwrap();
+        std::os::unix::fs::symlink(base.join("a.txt"), base.join("link.txt")).unwrap();
+        // a loop back to the top
+        std::os::unix::fs::symlink(base, base.join("sub/loop")).unwrap();
+        std::os::unix::fs::symlink(base.join("missing"), base.join("dangling.txt")).unwrap();
+
+        let filter = NameFilter::new(&[], &["*.log".into()]).unwrap();
+        assert_eq!(
+            walk_local(base, &filter, SymlinkPolicy::Skip).unwrap(),
+            vec!["a.txt", "sub/b.txt"]
+        );
+        assert_eq!(
+            walk_local(base, &filter, SymlinkPolicy::Follow).unwrap(),
+            vec!["a.txt", "dangling.txt", "link.txt", "sub/b.txt"]
+        );
+        assert!(walk_local(base, &filter, SymlinkPolicy::Error).is_err());
+    }
+
+    #[test]
+    fn test_put_tree() {
+        test_logger();
+
+        let tmp_dir = TempDir::new().unwrap();
+        let base = tmp_dir.path();
+        fs::create_dir_all(base.join("sub/empty")).unwrap();
+        fs::write(base.join("a.txt"), "a").unwrap();
+        fs::write(base.join("sub/b.txt"), "b").unwrap();
+
+        let server = MockServer::start();
+        let mkcol_top = server.mock(|when, then| {
+            when.is_true(|req| req.method_str() == "MKCOL")
+                .path("/ns/out");
+            then.status(405);
+        });
+        let mkcol_sub = server.mock(|when, then| {
+            when.is_true(|req| req.method_str() == "MKCOL")
+                .path("/ns/out/sub");
+            then.status(201);
+        });
+        let mkcol_empty = server.mock(|when, then| {
+            when.is_true(|req| req.method_str() == "MKCOL")
+                .path("/ns/out/sub/empty");
+            then.status(201);
+        });
+        let put_a = server.mock(|when, then| {
+            when.method(PUT).path("/ns/out/a.txt").body("a");
+            then.status(201);
+        });
+        let put_b = server.mock(|when, then| {
+            when.method(PUT).path("/ns/out/sub/b.txt").body("b");
+            then.status(201);
+        });
+
+        let cred = test_cred(&["storage.create:/"]);
+        let client = build_http_client(&cred).unwrap();
+        let info = PelicanInfo {
+            origins: vec![server.url("/ns")],
+            osdf_prefix: "osdf:///ns".into(),
+        };
+
+        let filter = NameFilter::new(&[], &[]).unwrap();
+        put_tree(
+            base,
+            "osdf:///ns/out/",
+            &filter,
+            SymlinkPolicy::Error,
+            &Reporter::default(),
+            &info,
+            &client,
+        )
+        .unwrap();
+
+        mkcol_top.assert();
+        mkcol_sub.assert();
+        mkcol_empty.assert_calls(0);
+        put_a.assert();
+        put_b.assert();
+
+        // a dangling symlink fails alone, and the rest is still uploaded
+        std::os::unix::fs::symlink(base.join("missing"), base.join("dangling.txt")).unwrap();
+        let err = put_tree(
+            base,
+            "osdf:///ns/out/",
+            &filter,
+            SymlinkPolicy::Follow,
+            &Reporter::default(),
+            &info,
+            &client,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("1 of 3 files failed"));
+        put_a.assert_calls(2);
+        put_b.assert_calls(2);
+    }
+
+    #[test]
+    fn test_parent_url() {
+        let info = PelicanInfo {
+            origins: vec![],
+            osdf_prefix: "osdf:///ns".into(),
+        };
+        assert_eq!(
+            parent_url("osdf:///ns/a/b/", &info).as_deref(),
+            Some("osdf:///ns/a")
+        );
+        assert_eq!(parent_url("osdf:///ns/a", &info), None);
+    }
+}
@@ -0,0 +1,230 @@
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::error::MyError;
+use crate::transfer::TransferResult;
+
+/// How results are printed on stdout.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutputFormat {
+    /// For people
+    #[default]
+    Text,
+    /// One JSON document per operation
+    Json,
+}
+
+/// An error, in a form that can be serialized and sent between threads.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct ErrorReport {
+    /// The `MyError` variant, or `Io` or `Other`
+    pub class: String,
+    pub message: String,
+    /// The whole `MyError`, if it was one
+    pub error: Option<MyError>,
+}
+
+impl ErrorReport {
+    pub fn new(e: &(dyn Error + 'static)) -> Self {
+        let error = e.downcast_ref::<MyError>().cloned();
+        let class = match &error {
+            Some(MyError::Credentials(_)) => "Credentials",
+            Some(MyError::Transfer(_)) => "Transfer",
+            Some(MyError::Pelican(_)) => "Pelican",
+            Some(MyError::Http { .. }) => "Http",
+            Some(MyError::Plugin(_)) => "Plugin",
+            Some(MyError::Stream(_)) => "Stream",
+            Some(MyError::Config(_)) => "Config",
+            Some(MyError::Generic(_)) => "Generic",
+            None if e.is::<std::io::Error>() => "Io",
+            None => "Other",
+        };
+        ErrorReport {
+            class: class.to_string(),
+            message: e.to_string(),
+            error,
+        }
+    }
+}
+
+/// The outcome of one operation, for `--output json`.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct OperationReport {
+    pub url: Option<String>,
+    pub path: Option<String>,
+    pub verb: String,
+    /// `success` or `failure`
+    pub status: &'static str,
+    pub bytes: Option<u64>,
+    pub duration: Option<f64>,
+    /// The origin url the data went to or came from
+    pub origin: Option<String>,
+    pub error: Option<ErrorReport>,
+}
+
+impl OperationReport {
+    pub fn new(
+        url: Option<&str>,
+        path: Option<&str>,
+        verb: &str,
+        result: Result<Option<&TransferResult>, ErrorReport>,
+    ) -> Self {
+        let mut ret = OperationReport {
+            url: url.map(String::from),
+            path: path.map(String::from),
+            verb: verb.to_string(),
+            status: "success",
+            bytes: None,
+            duration: None,
+            origin: None,
+            error: None,
+        };
+        match result {
+            Ok(Some(stats)) => {
+                ret.bytes = Some(stats.bytes);
+                ret.duration = Some(stats.duration);
+                ret.origin = Some(stats.origin_url.clone());
+            }
+            Ok(None) => {}
+            Err(e) => {
+                ret.status = "failure";
+                ret.error = Some(e);
+            }
+        }
+        ret
+    }
+}
+
+/// Set once anything has been reported, so a failure before the first
+/// operation can still be reported by `main`.
+static REPORTED: AtomicBool = AtomicBool::new(false);
+
+pub fn anything_reported() -> bool {
+    REPORTED.load(Ordering::SeqCst)
+}
+
+/// Prints results in the chosen format.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Reporter {
+    pub format: OutputFormat,
+    /// Print transfer statistics in text mode
+    pub stats: bool,
+    /// Print on stderr, because stdout carries data
+    pub stderr: bool,
+}
+
+impl Reporter {
+    pub fn is_json(&self) -> bool {
+        self.format == OutputFormat::Json
+    }
+
+    /// This reporter, switched to JSON by a command's own `--json` flag.
+    pub fn with_json(self, json: bool) -> Self {
+        if json {
+            Reporter {
+                format: OutputFormat::Json,
+                ..self
+            }
+        } else {
+            self
+        }
+    }
+
+    fn emit(&self, line: &str) {
+        if self.stderr {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    }
+
+    /// Print any serializable document as one line of JSON.
+    pub fn json<T: serde::Serialize>(&self, doc: &T) -> Result<(), Box<dyn Error>> {
+        REPORTED.store(true, Ordering::SeqCst);
+        self.emit(&serde_json::to_string(doc)?);
+        Ok(())
+    }
+
+    pub fn report(&self, report: &OperationReport) -> Result<(), Box<dyn Error>> {
+        if self.is_json() {
+            self.json(report)?;
+        }
+        Ok(())
+    }
+
+    /// Report a finished transfer: statistics with `--stats`, or a JSON document.
+    pub fn transfer(
+        &self,
+        url: &str,
+        path: &str,
+        verb: &str,
+        result: &Result<TransferResult, Box<dyn Error>>,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.is_json() {
+            let result = match result {
+                Ok(stats) => Ok(Some(stats)),
+                Err(e) => Err(ErrorReport::new(e.as_ref())),
+            };
+            self.json(&OperationReport::new(Some(url), Some(path), verb, result))
+        } else {
+            if self.stats
+                && let Ok(stats) = result
+            {
+                self.emit(&serde_json::to_string(stats)?);
+            }
+            Ok(())
+        }
+    }
+
+    /// Report an operation that moves no data, passing its result through.
+    pub fn operation(
+        &self,
+        url: &str,
+        verb: &str,
+        result: Result<(), Box<dyn Error>>,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.is_json() {
+            let report = match &result {
+                Ok(()) => Ok(None),
+                Err(e) => Err(ErrorReport::new(e.as_ref())),
+            };
+            self.json(&OperationReport::new(Some(url), None, verb, report))?;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_report() {
+        let err = MyError::Http {
+            url: "https://origin/ns/file".into(),
+            status: Some(404),
+            details: "not found".into(),
+        };
+        let report = ErrorReport::new(&err);
+        assert_eq!(report.class, "Http");
+        let doc = serde_json::to_value(&report).unwrap();
+        assert_eq!(doc["error"]["class"], "Http");
+        assert_eq!(doc["error"]["details"]["status"], 404);
+
+        let err = std::io::Error::new(std::io::ErrorKind::NotFound, "missing");
+        let report = ErrorReport::new(&err);
+        assert_eq!(report.class, "Io");
+        assert_eq!(report.error, None);
+
+        let doc = serde_json::to_value(OperationReport::new(
+            Some("osdf:///ns/file"),
+            Some("file"),
+            "get",
+            Err(ErrorReport::new(&MyError::Credentials("no token".into()))),
+        ))
+        .unwrap();
+        assert_eq!(doc["status"], "failure");
+        assert_eq!(doc["error"]["class"], "Credentials");
+        assert_eq!(doc["error"]["error"]["details"], "no token");
+    }
+}
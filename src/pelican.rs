@@ -1,36 +1,69 @@
+use std::collections::HashMap;
 use std::error::Error;
+use std::hash::Hash;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
 
-use memoize::memoize;
 use rand::seq::IndexedRandom;
 use reqwest::header::HeaderMap;
 
+use crate::config;
 use crate::error::MyError;
+use crate::output::Reporter;
 
-pub fn handle_link_header(header: &str) -> Result<Vec<&str>, Box<dyn Error>> {
+/// One target from a director's Link header.
+#[derive(serde::Serialize, Debug, PartialEq, Clone)]
+pub struct Link {
+    pub url: String,
+    /// Lower is preferred
+    pub priority: Option<u32>,
+    pub depth: Option<u32>,
+}
+
+/// Parse a Link header like `<url>; rel="duplicate"; pri=1; depth=2, <url>; ...`,
+/// ordered by priority.
+pub fn handle_link_header(header: &str) -> Result<Vec<Link>, Box<dyn Error>> {
     let mut ret = Vec::new();
     for line in header.split(',') {
-        let url = match match line.split_once('<') {
-            Some(part) => part.1,
-            None => {
-                return Err(Box::new(MyError::Pelican(
-                    "Error parsing link header".into(),
-                )));
-            }
-        }
-        .split_once('>')
+        let (url, params) = match line
+            .split_once('<')
+            .and_then(|(_, rest)| rest.split_once('>'))
         {
-            Some(part) => part.0,
+            Some(parts) => parts,
             None => {
                 return Err(Box::new(MyError::Pelican(
                     "Error parsing link header".into(),
                 )));
             }
         };
-        ret.push(url);
+        let mut link = Link {
+            url: url.to_string(),
+            priority: None,
+            depth: None,
+        };
+        for param in params.split(';') {
+            match param.trim().split_once('=') {
+                Some(("pri", value)) => link.priority = value.trim_matches('"').parse().ok(),
+                Some(("depth", value)) => link.depth = value.trim_matches('"').parse().ok(),
+                _ => {}
+            }
+        }
+        ret.push(link);
     }
+    // links without a priority go last, otherwise keep the director's order
+    ret.sort_by_key(|link| link.priority.unwrap_or(u32::MAX));
     Ok(ret)
 }
 
+/// Split a header like `key=value, key=value` into its fields.
+fn handle_fields_header(header: &str) -> Vec<(String, String)> {
+    header
+        .split(',')
+        .filter_map(|field| field.split_once('='))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect()
+}
+
 pub fn handle_namespace_header(header: &str) -> Result<&str, Box<dyn Error>> {
     Ok(
         match match header.split_once(',') {
@@ -54,26 +87,147 @@ pub fn handle_namespace_header(header: &str) -> Result<&str, Box<dyn Error>> {
 }
 
 const OSDF_URL_PREFIX: &str = "osdf://";
-const OSDF_DIRECTOR: &str = "https://osdf-director.osg-htc.org/api/v1.0/director/origin";
+const PELICAN_URL_PREFIX: &str = "pelican://";
+pub(crate) const OSDF_DIRECTOR: &str = "https://osdf-director.osg-htc.org/api/v1.0/director/origin";
+const DIRECTOR_ORIGIN_API: &str = "/api/v1.0/director/origin";
 
-#[derive(Debug, PartialEq, Clone)]
-struct DirectorInfo {
-    headers: HeaderMap,
+#[derive(serde::Deserialize)]
+struct FederationConfig {
+    director_endpoint: String,
+}
+
+/// Look up `key` in `cache`, or fetch it. Only successes are kept, so a
+/// transient failure is tried again by the next caller.
+fn cached<K: Eq + Hash + Clone, V: Clone>(
+    cache: &OnceLock<Mutex<HashMap<K, V>>>,
+    key: K,
+    fetch: impl FnOnce() -> Result<V, MyError>,
+) -> Result<V, MyError> {
+    let cache = cache.get_or_init(Default::default);
+    if let Some(value) = cache.lock().unwrap().get(&key) {
+        return Ok(value.clone());
+    }
+    let value = fetch()?;
+    cache.lock().unwrap().insert(key, value.clone());
+    Ok(value)
 }
 
-#[memoize]
-fn get_director_info(path: String) -> DirectorInfo {
-    let http_client = reqwest::blocking::ClientBuilder::new()
+fn director_client() -> reqwest::blocking::Client {
+    reqwest::blocking::ClientBuilder::new()
         // Following redirects opens the client up to SSRF vulnerabilities.
         .redirect(reqwest::redirect::Policy::none())
+        .timeout(Duration::from_secs(config::get().timeout.value))
+        .connect_timeout(Duration::from_secs(config::get().connect_timeout.value))
         .build()
-        .expect("Client should build");
+        .expect("Client should build")
+}
+
+/// Look up the director for a `pelican://` federation host
+/// via its `.well-known/pelican-configuration` document.
+fn get_federation_director(host: String) -> Result<String, MyError> {
+    static CACHE: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+    cached(&CACHE, host.clone(), || fetch_federation_director(&host))
+}
+
+fn fetch_federation_director(host: &str) -> Result<String, MyError> {
+    let http_client = director_client();
 
-    let director_url = format!("{}{}", OSDF_DIRECTOR, path);
-    let result = http_client
-        .get(director_url)
-        .send()
-        .expect("Cannot contact Pelican director");
+    let config_url = format!("https://{}/.well-known/pelican-configuration", host);
+    let result = match http_client.get(&config_url).send() {
+        Ok(r) => r,
+        Err(e) => {
+            return Err(MyError::Http {
+                url: config_url,
+                status: None,
+                details: format!("Cannot contact federation: {}", e),
+            });
+        }
+    };
+    if !result.status().is_success() {
+        return Err(MyError::Http {
+            url: config_url,
+            status: Some(result.status().as_u16()),
+            details: "Error getting federation config".into(),
+        });
+    }
+    let config: FederationConfig = result
+        .json()
+        .map_err(|e| MyError::Pelican(format!("Error parsing federation config: {}", e)))?;
+    Ok(format!(
+        "{}{}",
+        config.director_endpoint.trim_end_matches('/'),
+        DIRECTOR_ORIGIN_API
+    ))
+}
+
+/// Whether this is a url for a Pelican federation, as opposed to a local path.
+pub fn is_pelican_url(url: &str) -> bool {
+    url.starts_with(OSDF_URL_PREFIX) || url.starts_with(PELICAN_URL_PREFIX)
+}
+
+/// Split an `osdf://` or `pelican://` url into its federation prefix
+/// (scheme plus host), the director to ask, and the object path.
+/// The director can be overridden in the config.
+fn split_url(url: &str) -> Result<(String, String, String), Box<dyn Error>> {
+    let configured = config::get().settings_for(url).director;
+    if let Some((_, path)) = url.split_once(OSDF_URL_PREFIX) {
+        return Ok((
+            OSDF_URL_PREFIX.to_string(),
+            configured.unwrap_or_else(|| OSDF_DIRECTOR.to_string()),
+            path.to_string(),
+        ));
+    }
+    if let Some((_, rest)) = url.split_once(PELICAN_URL_PREFIX) {
+        let (host, path) = match rest.find('/') {
+            Some(n) => rest.split_at(n),
+            None => (rest, ""),
+        };
+        if host.is_empty() {
+            return Err(Box::new(MyError::Pelican(
+                "pelican url is missing the federation host".into(),
+            )));
+        }
+        let director = match configured {
+            Some(director) => director,
+            None => get_federation_director(host.to_string())?,
+        };
+        return Ok((
+            format!("{}{}", PELICAN_URL_PREFIX, host),
+            director,
+            path.to_string(),
+        ));
+    }
+    Err(Box::new(MyError::Pelican(
+        "url is not an OSDF or Pelican url".into(),
+    )))
+}
+
+#[derive(Debug, PartialEq, Clone)]
+struct DirectorInfo {
+    headers: HeaderMap,
+}
+
+fn get_director_info(director: String, path: String) -> Result<DirectorInfo, MyError> {
+    static CACHE: OnceLock<Mutex<HashMap<(String, String), DirectorInfo>>> = OnceLock::new();
+    cached(&CACHE, (director.clone(), path.clone()), || {
+        fetch_director_info(&director, &path)
+    })
+}
+
+fn fetch_director_info(director: &str, path: &str) -> Result<DirectorInfo, MyError> {
+    let http_client = director_client();
+
+    let director_url = format!("{}{}", director, path);
+    let result = match http_client.get(&director_url).send() {
+        Ok(r) => r,
+        Err(e) => {
+            return Err(MyError::Http {
+                url: director_url,
+                status: None,
+                details: format!("Cannot contact Pelican director: {}", e),
+            });
+        }
+    };
 
     match result.status().as_u16() {
         n if n >= 400 => {
@@ -81,72 +235,149 @@ fn get_director_info(path: String) -> DirectorInfo {
                 Ok(t) => t,
                 Err(_) => "".into(),
             };
-            panic!("Error finding Pelican Origin: {}", text)
+            Err(MyError::Http {
+                url: director_url,
+                status: Some(n),
+                details: format!("Error finding Pelican Origin: {}", text),
+            })
         }
         _ => {
             let headers = result.headers();
-            DirectorInfo {
+            Ok(DirectorInfo {
                 headers: headers.clone(),
-            }
+            })
         }
     }
 }
 
-pub struct PelicanInfo {
-    pub(crate) origins: Vec<String>,
-    pub(crate) osdf_prefix: String,
+/// The director that will be asked about this url, if it can be determined.
+pub fn get_director_url(url: &str) -> Option<String> {
+    split_url(url).ok().map(|(_, director, _)| director)
 }
 
-impl PelicanInfo {
-    pub fn from_url(url: &str) -> Result<Self, Box<dyn Error>> {
-        let path = match url.split_once(OSDF_URL_PREFIX) {
-            Some((_, s2)) => s2,
-            None => {
-                return Err(Box::new(MyError::Pelican("url is not an OSDF url".into())));
-            }
-        };
+/// Everything the director says about the namespace of a url.
+#[derive(serde::Serialize, Debug, PartialEq)]
+pub struct NamespaceInfo {
+    pub namespace: String,
+    pub osdf_prefix: String,
+    /// Origins and caches, in order of priority
+    pub links: Vec<Link>,
+    pub require_token: Option<bool>,
+    pub collections_url: Option<String>,
+    /// Token issuers from X-Pelican-Authorization
+    pub issuers: Vec<String>,
+}
 
-        let director_info = get_director_info(path.to_string());
-        let headers = director_info.headers;
+impl NamespaceInfo {
+    pub fn from_url(url: &str) -> Result<Self, Box<dyn Error>> {
+        let (url_prefix, director, path) = split_url(url)?;
+        let director_info = get_director_info(director, path)?;
+        Self::from_headers(&url_prefix, &director_info.headers)
+    }
 
-        let namespace = match headers.get("x-pelican-namespace") {
-            Some(parts) => handle_namespace_header(parts.to_str()?)?,
+    fn from_headers(url_prefix: &str, headers: &HeaderMap) -> Result<Self, Box<dyn Error>> {
+        let namespace_header = match headers.get("x-pelican-namespace") {
+            Some(parts) => parts.to_str()?,
             None => {
                 return Err(Box::new(MyError::Pelican(
-                    "No link header when locating origins".into(),
+                    "No x-pelican-namespace header when locating origins".into(),
                 )));
             }
         };
+        let namespace = handle_namespace_header(namespace_header)?;
         log::info!("pelican namespace: {}", namespace);
+        let fields = handle_fields_header(namespace_header);
+        let field = |name: &str| {
+            fields
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.clone())
+        };
 
-        let origins = match headers.get("link") {
-            Some(links) => {
-                let mut ret = Vec::new();
-                for link in handle_link_header(links.to_str()?)? {
-                    match link.split_inclusive(namespace).next() {
-                        Some(prefix) => {
-                            ret.push(prefix.to_string());
-                        }
-                        None => {
-                            return Err(Box::new(MyError::Pelican(
-                                "Origin link does not contain namespace".into(),
-                            )));
-                        }
-                    }
-                }
-                ret
-            }
+        let links = match headers.get("link") {
+            Some(links) => handle_link_header(links.to_str()?)?,
             None => {
                 return Err(Box::new(MyError::Pelican(
                     "No link header when locating origins".into(),
                 )));
             }
         };
+
+        let mut issuers = Vec::new();
+        for value in headers.get_all("x-pelican-authorization").iter() {
+            for (k, v) in handle_fields_header(value.to_str()?) {
+                if k == "issuer" {
+                    issuers.push(v);
+                }
+            }
+        }
+
+        Ok(Self {
+            namespace: namespace.to_string(),
+            osdf_prefix: format!("{}{}", url_prefix, namespace),
+            links,
+            require_token: field("require-token").map(|v| v == "true"),
+            collections_url: field("collections-url"),
+            issuers,
+        })
+    }
+}
+
+/// Print what the director says about the namespace of `url`.
+pub fn print_namespace_info(url: &str, report: &Reporter) -> Result<(), Box<dyn Error>> {
+    let info = NamespaceInfo::from_url(url)?;
+    if report.is_json() {
+        return report.json(&info);
+    }
+    let or_unknown = |v: Option<String>| v.unwrap_or_else(|| "unknown".into());
+    println!("namespace:       {}", info.namespace);
+    println!("prefix:          {}", info.osdf_prefix);
+    println!(
+        "require token:   {}",
+        or_unknown(info.require_token.map(|b| b.to_string()))
+    );
+    println!("collections url: {}", or_unknown(info.collections_url));
+    println!("issuers:         {}", info.issuers.join(" "));
+    println!("links:");
+    for link in info.links.iter() {
+        let show = |v: Option<u32>| v.map_or("-".to_string(), |n| n.to_string());
+        println!(
+            "  pri={:<3} depth={:<3} {}",
+            show(link.priority),
+            show(link.depth),
+            link.url
+        );
+    }
+    Ok(())
+}
+
+pub struct PelicanInfo {
+    pub(crate) origins: Vec<String>,
+    pub(crate) osdf_prefix: String,
+}
+
+impl PelicanInfo {
+    pub fn from_url(url: &str) -> Result<Self, Box<dyn Error>> {
+        let info = NamespaceInfo::from_url(url)?;
+
+        let mut origins = Vec::new();
+        for link in info.links.iter() {
+            match link.url.split_inclusive(&info.namespace).next() {
+                Some(prefix) => {
+                    origins.push(prefix.to_string());
+                }
+                None => {
+                    return Err(Box::new(MyError::Pelican(
+                        "Origin link does not contain namespace".into(),
+                    )));
+                }
+            }
+        }
         log::info!("origin urls: {:?}", origins);
 
         Ok(Self {
             origins,
-            osdf_prefix: format!("{}{}", OSDF_URL_PREFIX, namespace),
+            osdf_prefix: info.osdf_prefix,
         })
     }
 
@@ -176,4 +407,99 @@ mod tests {
         assert_eq!(info.get_osdf_prefix(), "osdf:///icecube/wipac");
         info.choose_origin().unwrap();
     }
+
+    #[test]
+    fn test_director_errors_are_not_cached() {
+        test_logger();
+
+        let server = httpmock::MockServer::start();
+        let mut failing = server.mock(|when, then| {
+            when.path("/ns/file");
+            then.status(500);
+        });
+        assert!(get_director_info(server.url(""), "/ns/file".into()).is_err());
+        failing.delete();
+
+        let working = server.mock(|when, then| {
+            when.path("/ns/file");
+            then.status(307)
+                .header("x-pelican-namespace", "namespace=/ns");
+        });
+        for _ in 0..2 {
+            assert!(get_director_info(server.url(""), "/ns/file".into()).is_ok());
+        }
+        working.assert_calls(1);
+    }
+
+    #[test]
+    fn test_split_url() {
+        let (prefix, director, path) = split_url("osdf:///icecube/wipac/file.bin").unwrap();
+        assert_eq!(prefix, "osdf://");
+        assert_eq!(director, OSDF_DIRECTOR);
+        assert_eq!(path, "/icecube/wipac/file.bin");
+
+        assert!(split_url("https://example.com/file.bin").is_err());
+        assert!(split_url("pelican:///no/host").is_err());
+    }
+
+    #[test]
+    fn test_handle_link_header() {
+        let links = handle_link_header(
+            r#"<https://cache:8443/ns/file>; rel="duplicate"; pri=2; depth=2, <https://origin:8443/ns/file>; rel="duplicate"; pri=1; depth=2, <https://other/ns/file>"#,
+        )
+        .unwrap();
+        let urls: Vec<_> = links.iter().map(|l| l.url.as_str()).collect();
+        assert_eq!(
+            urls,
+            vec![
+                "https://origin:8443/ns/file",
+                "https://cache:8443/ns/file",
+                "https://other/ns/file"
+            ]
+        );
+        assert_eq!(links[0].priority, Some(1));
+        assert_eq!(links[0].depth, Some(2));
+        assert_eq!(links[2].priority, None);
+
+        assert!(handle_link_header("no brackets").is_err());
+    }
+
+    #[test]
+    fn test_namespace_info_from_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "link",
+            r#"<https://origin:8443/icecube/wipac/file>; rel="duplicate"; pri=1; depth=3"#
+                .parse()
+                .unwrap(),
+        );
+        headers.insert(
+            "x-pelican-namespace",
+            "namespace=/icecube/wipac, require-token=true, collections-url=https://origin:8443"
+                .parse()
+                .unwrap(),
+        );
+        headers.append(
+            "x-pelican-authorization",
+            "issuer=https://issuer-a".parse().unwrap(),
+        );
+        headers.append(
+            "x-pelican-authorization",
+            "issuer=https://issuer-b".parse().unwrap(),
+        );
+
+        let info = NamespaceInfo::from_headers("osdf://", &headers).unwrap();
+        assert_eq!(info.namespace, "/icecube/wipac");
+        assert_eq!(info.osdf_prefix, "osdf:///icecube/wipac");
+        assert_eq!(info.require_token, Some(true));
+        assert_eq!(
+            info.collections_url,
+            Some("https://origin:8443".to_string())
+        );
+        assert_eq!(info.issuers, vec!["https://issuer-a", "https://issuer-b"]);
+        assert_eq!(info.links.len(), 1);
+
+        headers.remove("link");
+        assert!(NamespaceInfo::from_headers("osdf://", &headers).is_err());
+    }
 }
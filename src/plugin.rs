@@ -0,0 +1,406 @@
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::ExitCode;
+
+use crate::classad::{self, ClassAd, Value};
+use crate::config;
+use crate::credentials::Credentials;
+use crate::error::MyError;
+use crate::logging;
+use crate::pelican::{self, PelicanInfo};
+use crate::transfer::{Transfer, TransferResult, Verb};
+
+const PLUGIN_TYPE: &str = "FileTransfer";
+const SUPPORTED_METHODS: &str = "osdf,pelican";
+
+/// Arguments HTCondor passes to a file transfer plugin.
+#[derive(Debug, PartialEq, Default)]
+struct PluginArgs {
+    classad: bool,
+    infile: Option<String>,
+    outfile: Option<String>,
+    upload: bool,
+}
+
+/// HTCondor calls plugins with single-dash long options,
+/// which clap does not handle, so detect them up front.
+pub fn is_plugin_invocation(args: &[String]) -> bool {
+    args.iter().any(|a| a == "-classad" || a == "-infile")
+}
+
+fn parse_args(args: &[String]) -> Result<PluginArgs, Box<dyn Error>> {
+    let mut ret = PluginArgs::default();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-classad" => ret.classad = true,
+            "-upload" => ret.upload = true,
+            "-infile" => match iter.next() {
+                Some(f) => ret.infile = Some(f.clone()),
+                None => return Err(Box::new(MyError::Plugin("-infile needs a value".into()))),
+            },
+            "-outfile" => match iter.next() {
+                Some(f) => ret.outfile = Some(f.clone()),
+                None => {
+                    return Err(Box::new(MyError::Plugin("-outfile needs a value".into())));
+                }
+            },
+            other => log::warn!("ignoring unknown plugin argument {}", other),
+        }
+    }
+    Ok(ret)
+}
+
+pub fn capability_ad() -> ClassAd {
+    let mut ad = ClassAd::new();
+    ad.insert(
+        "PluginVersion",
+        Value::Str(env!("CARGO_PKG_VERSION").into()),
+    );
+    ad.insert("PluginType", Value::Str(PLUGIN_TYPE.into()));
+    ad.insert("SupportedMethods", Value::Str(SUPPORTED_METHODS.into()));
+    ad.insert("MultipleFileSupport", Value::Bool(true));
+    ad
+}
+
+fn url_host(url: &str) -> Option<String> {
+    let url = reqwest::Url::parse(url).ok()?;
+    let host = url.host_str()?;
+    Some(match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    })
+}
+
+/// Describe a failed transfer the way HTCondor expects in `TransferErrorData`,
+/// so hold reasons point at the service that actually broke.
+fn error_data(err: &(dyn Error + 'static), url: &str) -> ClassAd {
+    let director = pelican::get_director_url(url).and_then(|d| url_host(&d));
+
+    let mut code = None;
+    let mut failed_server = None;
+    let mut intermediate_server = None;
+    let error_type = match err.downcast_ref::<MyError>() {
+        Some(MyError::Credentials(_)) => "Authorization",
+        Some(MyError::Pelican(_)) if director.is_none() => "Specification",
+        Some(MyError::Pelican(_)) => {
+            failed_server = director.clone();
+            "Contact Services"
+        }
+        Some(MyError::Http {
+            url: failed_url,
+            status,
+            ..
+        }) => {
+            code = status.map(i64::from);
+            failed_server = url_host(failed_url);
+            let at_origin = director.is_some() && failed_server != director;
+            if at_origin {
+                intermediate_server = director.clone();
+            }
+            match status {
+                Some(401) | Some(403) => "Authorization",
+                Some(404) => "Specification",
+                _ if at_origin => "Transfer",
+                _ => "Contact Services",
+            }
+        }
+        Some(MyError::Plugin(_)) => "Specification",
+        Some(_) => "Transfer",
+        None => match err.downcast_ref::<std::io::Error>() {
+            Some(e) => {
+                code = e.raw_os_error().map(i64::from);
+                match e.kind() {
+                    std::io::ErrorKind::NotFound | std::io::ErrorKind::PermissionDenied => {
+                        "Specification"
+                    }
+                    _ => "Transfer",
+                }
+            }
+            None => "Transfer",
+        },
+    };
+
+    let mut ad = ClassAd::new();
+    ad.insert("ErrorType", Value::Str(error_type.into()));
+    if let Some(code) = code {
+        ad.insert("ErrorCode", Value::Int(code));
+    }
+    if let Some(server) = failed_server {
+        ad.insert("FailedServer", Value::Str(server));
+    }
+    if let Some(server) = intermediate_server {
+        ad.insert("IntermediateServer", Value::Str(server));
+    }
+    ad
+}
+
+fn transfer_file(
+    url: &str,
+    local_file: &str,
+    mode: Verb,
+    creds: &Credentials,
+) -> Result<TransferResult, Box<dyn Error>> {
+    let transfer = Transfer::new(url.to_string(), local_file.to_string(), mode);
+    let origin = PelicanInfo::from_url(transfer.url.as_str())?;
+    transfer.execute(creds, &origin)
+}
+
+/// Run one transfer request, turning the outcome into a result ad.
+fn handle_request(request: &ClassAd, creds: &Result<Credentials, String>, upload: bool) -> ClassAd {
+    let url = request.get_str("Url").unwrap_or_default();
+    let local_file = request.get_str("LocalFileName").unwrap_or_default();
+
+    let mut ad = ClassAd::new();
+    ad.insert("TransferUrl", Value::Str(url.into()));
+    ad.insert(
+        "TransferFileName",
+        Value::Str(
+            Path::new(local_file)
+                .file_name()
+                .and_then(|f| f.to_str())
+                .unwrap_or(local_file)
+                .into(),
+        ),
+    );
+    ad.insert(
+        "TransferProtocol",
+        Value::Str(url.split_once("://").map(|p| p.0).unwrap_or("").into()),
+    );
+    ad.insert(
+        "TransferType",
+        Value::Str(if upload { "upload" } else { "download" }.into()),
+    );
+
+    let result = if url.is_empty() || local_file.is_empty() {
+        Err(Box::new(MyError::Plugin(
+            "transfer request is missing Url or LocalFileName".into(),
+        )) as Box<dyn Error>)
+    } else {
+        match creds {
+            Ok(creds) => {
+                let mode = if upload { Verb::Put } else { Verb::Get };
+                transfer_file(url, local_file, mode, creds)
+            }
+            Err(e) => Err(Box::new(MyError::Credentials(e.clone())) as Box<dyn Error>),
+        }
+    };
+
+    match result {
+        Ok(stats) => {
+            ad.insert("TransferSuccess", Value::Bool(true));
+            ad.insert("TransferTotalBytes", Value::Int(stats.bytes as i64));
+            ad.insert("TransferFileBytes", Value::Int(stats.bytes as i64));
+            ad.insert("TransferStartTime", Value::Int(stats.start_time as i64));
+            ad.insert("TransferEndTime", Value::Int(stats.end_time as i64));
+            ad.insert("ConnectionTimeSeconds", Value::Real(stats.duration));
+            if let Some(time_to_first_byte) = stats.time_to_first_byte {
+                ad.insert("TimeToFirstByte", Value::Real(time_to_first_byte));
+            }
+            ad.insert("TransferOriginUrl", Value::Str(stats.origin_url));
+            ad.insert("Attempts", Value::Int(stats.attempts.into()));
+        }
+        Err(e) => {
+            log::error!("Error transferring {}: {}", url, e);
+            ad.insert("TransferSuccess", Value::Bool(false));
+            ad.insert("TransferError", Value::Str(e.to_string()));
+            ad.insert(
+                "TransferErrorData",
+                Value::List(vec![Value::Ad(error_data(e.as_ref(), url))]),
+            );
+        }
+    }
+    ad
+}
+
+/// Process every request in the infile, appending one result ad per
+/// request to the outfile. Returns whether all transfers succeeded.
+fn run_transfers(infile: &str, outfile: &str, upload: bool) -> Result<bool, Box<dyn Error>> {
+    let requests = classad::parse_ads(&fs::read_to_string(infile)?)?;
+    log::info!("plugin got {} transfer requests", requests.len());
+
+    let creds = Credentials::discover().map_err(|e| match e.downcast_ref::<MyError>() {
+        Some(MyError::Credentials(details)) => details.clone(),
+        _ => e.to_string(),
+    });
+
+    // write each result as it finishes, so partial progress is kept
+    let mut out = fs::File::create(outfile)?;
+    let mut success = true;
+    for request in requests.iter() {
+        let ad = handle_request(request, &creds, upload);
+        if ad.get("TransferSuccess") != Some(&Value::Bool(true)) {
+            success = false;
+        }
+        writeln!(out, "{ad}")?;
+        out.flush()?;
+    }
+    Ok(success)
+}
+
+fn run(args: &[String]) -> Result<bool, Box<dyn Error>> {
+    let args = parse_args(args)?;
+    if args.classad {
+        print!("{}", capability_ad().to_old_format());
+        return Ok(true);
+    }
+    match (args.infile, args.outfile) {
+        (Some(infile), Some(outfile)) => run_transfers(&infile, &outfile, args.upload),
+        _ => Err(Box::new(MyError::Plugin(
+            "both -infile and -outfile are required".into(),
+        ))),
+    }
+}
+
+/// Entry point when invoked by HTCondor as a file transfer plugin.
+pub fn main(args: &[String]) -> ExitCode {
+    let _log_handle = logging::configure_logging(logging::LOG_DEFAULT_LEVEL);
+    match config::Config::load(&config::config_paths(None), &Default::default()) {
+        Ok(config) => config::init(config),
+        Err(e) => log::warn!("Ignoring config: {e}"),
+    }
+    match run(args) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            log::error!("Error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::logging::test_logger;
+
+    fn to_args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_is_plugin_invocation() {
+        assert!(is_plugin_invocation(&to_args(&["-classad"])));
+        assert!(is_plugin_invocation(&to_args(&[
+            "-infile", "in", "-outfile", "out"
+        ])));
+        assert!(!is_plugin_invocation(&to_args(&[
+            "object", "get", "url", "file"
+        ])));
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let args = parse_args(&to_args(&["-infile", "in", "-outfile", "out", "-upload"])).unwrap();
+        assert_eq!(
+            args,
+            PluginArgs {
+                classad: false,
+                infile: Some("in".into()),
+                outfile: Some("out".into()),
+                upload: true,
+            }
+        );
+        assert!(parse_args(&to_args(&["-infile"])).is_err());
+    }
+
+    #[test]
+    fn test_capability_ad() {
+        let ad = capability_ad();
+        assert_eq!(ad.get_str("PluginType"), Some("FileTransfer"));
+        assert_eq!(ad.get_str("SupportedMethods"), Some("osdf,pelican"));
+        assert!(
+            ad.to_old_format()
+                .contains("SupportedMethods = \"osdf,pelican\"\n")
+        );
+    }
+
+    #[test]
+    fn test_error_data() {
+        let url = "osdf:///ns/file";
+
+        let err = MyError::Credentials("No matching credentials for url".into());
+        let ad = error_data(&err, url);
+        assert_eq!(ad.get_str("ErrorType"), Some("Authorization"));
+
+        let err = MyError::Http {
+            url: "https://osdf-director.osg-htc.org/api/v1.0/director/origin/ns/file".into(),
+            status: Some(500),
+            details: "".into(),
+        };
+        let ad = error_data(&err, url);
+        assert_eq!(ad.get_str("ErrorType"), Some("Contact Services"));
+        assert_eq!(ad.get("ErrorCode"), Some(&Value::Int(500)));
+        assert_eq!(
+            ad.get_str("FailedServer"),
+            Some("osdf-director.osg-htc.org")
+        );
+        assert_eq!(ad.get("IntermediateServer"), None);
+
+        let err = MyError::Http {
+            url: "https://origin.example.com:8443/ns/file".into(),
+            status: Some(403),
+            details: "".into(),
+        };
+        let ad = error_data(&err, url);
+        assert_eq!(ad.get_str("ErrorType"), Some("Authorization"));
+        assert_eq!(ad.get_str("FailedServer"), Some("origin.example.com:8443"));
+        assert_eq!(
+            ad.get_str("IntermediateServer"),
+            Some("osdf-director.osg-htc.org")
+        );
+
+        let err = MyError::Http {
+            url: "https://origin.example.com:8443/ns/file".into(),
+            status: None,
+            details: "connection refused".into(),
+        };
+        let ad = error_data(&err, url);
+        assert_eq!(ad.get_str("ErrorType"), Some("Transfer"));
+        assert_eq!(ad.get("ErrorCode"), None);
+
+        let err = MyError::Pelican("url is not an OSDF or Pelican url".into());
+        let ad = error_data(&err, "https://example.com/file");
+        assert_eq!(ad.get_str("ErrorType"), Some("Specification"));
+
+        let err = std::io::Error::from(std::io::ErrorKind::NotFound);
+        let ad = error_data(&err, url);
+        assert_eq!(ad.get_str("ErrorType"), Some("Specification"));
+    }
+
+    #[test]
+    fn test_run_transfers_bad_request() {
+        test_logger();
+
+        let tmp_dir = TempDir::new().unwrap();
+        let infile = tmp_dir.path().join("in");
+        let outfile = tmp_dir.path().join("out");
+        fs::write(&infile, "[ Url = \"osdf:///ns/file\" ]\n").unwrap();
+
+        temp_env::with_var("_CONDOR_CREDS", Some(tmp_dir.path().as_os_str()), || {
+            let success =
+                run_transfers(infile.to_str().unwrap(), outfile.to_str().unwrap(), false).unwrap();
+            assert!(!success);
+        });
+
+        let results = classad::parse_ads(&fs::read_to_string(&outfile).unwrap()).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].get("TransferSuccess"), Some(&Value::Bool(false)));
+        assert_eq!(results[0].get_str("TransferUrl"), Some("osdf:///ns/file"));
+        assert_eq!(results[0].get_str("TransferType"), Some("download"));
+        assert!(results[0].get_str("TransferError").is_some());
+        match results[0].get("TransferErrorData") {
+            Some(Value::List(items)) => match items.first() {
+                Some(Value::Ad(data)) => {
+                    assert_eq!(data.get_str("ErrorType"), Some("Specification"))
+                }
+                other => panic!("unexpected error data {:?}", other),
+            },
+            other => panic!("unexpected error data {:?}", other),
+        }
+    }
+}
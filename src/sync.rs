@@ -0,0 +1,627 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::credentials::Credentials;
+use crate::error::MyError;
+use crate::object::{self, NameFilter, SymlinkPolicy};
+use crate::output::{ErrorReport, OperationReport, Reporter};
+use crate::pelican::{self, PelicanInfo};
+use crate::transfer::{Transfer, TransferResult, Verb, build_http_client, get_origin_url};
+use crate::webdav;
+
+pub struct SyncOptions {
+    /// Compare adler32 checksums instead of modification times
+    pub checksum: bool,
+    /// Delete files in the destination that are not in the source
+    pub delete: bool,
+    /// Only print what would be done
+    pub dry_run: bool,
+    /// Don't read local files, as the global `--dry-run` promises, so
+    /// compare sizes and mtimes even with `checksum`
+    pub no_local_reads: bool,
+}
+
+impl SyncOptions {
+    fn compares_checksums(&self) -> bool {
+        self.checksum && !self.no_local_reads
+    }
+}
+
+/// What we know about one file on either side.
+#[derive(Debug, PartialEq, Clone, Default)]
+struct FileInfo {
+    size: Option<u64>,
+    /// Unix time, in whole seconds
+    mtime: Option<u64>,
+    checksum: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    Copy(String),
+    Delete(String),
+}
+
+fn adler32(path: &Path) -> Result<String, Box<dyn Error>> {
+    const MOD_ADLER: u32 = 65521;
+    let mut file = fs::File::open(path)?;
+    let mut a: u32 = 1;
+    let mut b: u32 = 0;
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        for &byte in buf[..n].iter() {
+            a = (a + byte as u32) % MOD_ADLER;
+            b = (b + a) % MOD_ADLER;
+        }
+    }
+    Ok(format!("{:08x}", (b << 16) | a))
+}
+
+/// Whether `src` has to be copied over `dst`.
+///
+/// Uploaded files get the upload time as their remote mtime, so for uploads
+/// only a local file newer than the remote one counts as changed.
+fn needs_copy(src: &FileInfo, dst: Option<&FileInfo>, upload: bool, checksum: bool) -> bool {
+    let dst = match dst {
+        Some(d) => d,
+        None => return true,
+    };
+    if src.size.is_none() || src.size != dst.size {
+        return true;
+    }
+    if checksum {
+        return match (&src.checksum, &dst.checksum) {
+            (Some(a), Some(b)) => !a.eq_ignore_ascii_case(b),
+            _ => true,
+        };
+    }
+    match (src.mtime, dst.mtime) {
+        (Some(s), Some(d)) if upload => s > d,
+        (Some(s), Some(d)) => s != d,
+        _ => true,
+    }
+}
+
+fn plan(
+    src: &BTreeMap<String, FileInfo>,
+    dst: &BTreeMap<String, FileInfo>,
+    upload: bool,
+    options: &SyncOptions,
+) -> Vec<Action> {
+    let mut ret = Vec::new();
+    for (name, info) in src.iter() {
+        if needs_copy(info, dst.get(name), upload, options.compares_checksums()) {
+            ret.push(Action::Copy(name.clone()));
+        }
+    }
+    if options.delete {
+        for name in dst.keys() {
+            if !src.contains_key(name) {
+                ret.push(Action::Delete(name.clone()));
+            }
+        }
+    }
+    ret
+}
+
+/// The files under `dir`. Only a destination may be missing, since a
+/// missing source would plan to delete everything with `--delete`.
+fn local_files(dir: &Path, missing_ok: bool) -> Result<BTreeMap<String, FileInfo>, Box<dyn Error>> {
+    let mut ret = BTreeMap::new();
+    if !dir.exists() {
+        if missing_ok {
+            return Ok(ret);
+        }
+        return Err(Box::new(MyError::Transfer(format!(
+            "sync source {} does not exist",
+            dir.display()
+        ))));
+    }
+    let filter = NameFilter::new(&[], &[])?;
+    for name in object::walk_local(dir, &filter, SymlinkPolicy::Follow)? {
+        let metadata = match fs::metadata(dir.join(&name)) {
+            Ok(metadata) => metadata,
+            // a dangling symlink; its upload fails on its own
+            Err(_) => {
+                ret.insert(
+                    name,
+                    FileInfo {
+                        size: None,
+                        mtime: None,
+                        checksum: None,
+                    },
+                );
+                continue;
+            }
+        };
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .ok();
+        ret.insert(
+            name,
+            FileInfo {
+                size: Some(metadata.len()),
+                mtime,
+                checksum: None,
+            },
+        );
+    }
+    Ok(ret)
+}
+
+/// The files under a collection, which only a destination may be missing.
+fn remote_files(
+    http_client: &reqwest::blocking::Client,
+    origin_url: &str,
+    missing_ok: bool,
+) -> Result<BTreeMap<String, FileInfo>, Box<dyn Error>> {
+    let entries = match webdav::list(http_client, origin_url, true) {
+        Ok(entries) => entries,
+        Err(e) => match e.downcast_ref::<MyError>() {
+            // nothing there yet
+            Some(MyError::Http {
+                status: Some(404), ..
+            }) if missing_ok => Vec::new(),
+            _ => return Err(e),
+        },
+    };
+    let mut ret = BTreeMap::new();
+    for entry in entries.into_iter().filter(|e| !e.is_collection) {
+        let mtime = entry
+            .mtime
+            .as_deref()
+            .and_then(|m| httpdate::parse_http_date(m).ok())
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs());
+        ret.insert(
+            entry.name,
+            FileInfo {
+                size: entry.size,
+                mtime,
+                checksum: None,
+            },
+        );
+    }
+    Ok(ret)
+}
+
+/// Fill in checksums for files on both sides with the same size,
+/// since those are the only ones where the checksum decides anything.
+fn add_checksums(
+    local_dir: &Path,
+    local: &mut BTreeMap<String, FileInfo>,
+    remote: &mut BTreeMap<String, FileInfo>,
+    http_client: &reqwest::blocking::Client,
+    origin_url: &str,
+) -> Result<(), Box<dyn Error>> {
+    for (name, local_info) in local.iter_mut() {
+        let remote_info = match remote.get_mut(name) {
+            Some(r) if local_info.size.is_some() && r.size == local_info.size => r,
+            _ => continue,
+        };
+        local_info.checksum = Some(adler32(&local_dir.join(name))?);
+        let url = format!(
+            "{}/{}",
+            origin_url.trim_end_matches('/'),
+            webdav::encode_path(name)
+        );
+        remote_info.checksum = webdav::stat(http_client, &url)?
+            .checksums
+            .get("adler32")
+            .cloned();
+        if remote_info.checksum.is_none() {
+            log::warn!("origin has no adler32 checksum for {}", name);
+        }
+    }
+    Ok(())
+}
+
+fn print_plan(actions: &[Action], upload: bool, report: &Reporter) -> Result<(), Box<dyn Error>> {
+    for action in actions.iter() {
+        let (verb, name) = match action {
+            Action::Copy(name) if upload => ("put", name),
+            Action::Copy(name) => ("get", name),
+            Action::Delete(name) => ("delete", name),
+        };
+        if report.is_json() {
+            report.json(&serde_json::json!({"verb": verb, "name": name, "status": "planned"}))?;
+        } else {
+            println!("{} {}", verb, name);
+        }
+    }
+    Ok(())
+}
+
+fn report_action(
+    report: &Reporter,
+    url: &str,
+    path: &str,
+    verb: &str,
+    result: &Result<Option<TransferResult>, Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let result = match result {
+        Ok(stats) => Ok(stats.as_ref()),
+        Err(e) => Err(ErrorReport::new(e.as_ref())),
+    };
+    report.report(&OperationReport::new(Some(url), Some(path), verb, result))
+}
+
+fn failures(failed: usize, total: usize) -> Result<(), Box<dyn Error>> {
+    if failed > 0 {
+        return Err(Box::new(MyError::Transfer(format!(
+            "{} of {} sync actions failed",
+            failed, total
+        ))));
+    }
+    Ok(())
+}
+
+fn sync_download(
+    url: &str,
+    dir: &Path,
+    options: &SyncOptions,
+    report: &Reporter,
+    origin: &PelicanInfo,
+    http_client: &reqwest::blocking::Client,
+) -> Result<(), Box<dyn Error>> {
+    let origin_url = get_origin_url(url, origin)?;
+    let mut remote = remote_files(http_client, &origin_url, false)?;
+    let mut local = local_files(dir, true)?;
+    if options.compares_checksums() {
+        add_checksums(dir, &mut local, &mut remote, http_client, &origin_url)?;
+    }
+
+    let actions = plan(&remote, &local, false, options);
+    if options.dry_run {
+        print_plan(&actions, false, report)?;
+        return Ok(());
+    }
+
+    let mut failed = 0;
+    for action in actions.iter() {
+        let (name, verb) = match action {
+            Action::Copy(name) => (name, "get"),
+            Action::Delete(name) => (name, "delete"),
+        };
+        let action_url = format!(
+            "{}/{}",
+            url.trim_end_matches('/'),
+            webdav::encode_path(name)
+        );
+        let path = dir.join(name).to_string_lossy().into_owned();
+        let result = match action {
+            Action::Copy(name) => object::safe_local_path(dir, name).and_then(|local_path| {
+                log::info!("getting {}", name);
+                if let Some(parent) = local_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let stats = Transfer::new(
+                    action_url.clone(),
+                    local_path.to_string_lossy().into_owned(),
+                    Verb::Get,
+                )
+                .execute_with_client(origin, http_client)?;
+                // match the remote mtime, so the next sync sees no change
+                if let Some(mtime) = remote.get(name).and_then(|r| r.mtime) {
+                    fs::File::options()
+                        .write(true)
+                        .open(&local_path)?
+                        .set_modified(UNIX_EPOCH + Duration::from_secs(mtime))?;
+                }
+                Ok(Some(stats))
+            }),
+            Action::Delete(name) => object::safe_local_path(dir, name).and_then(|local_path| {
+                log::info!("deleting {}", name);
+                fs::remove_file(local_path)?;
+                Ok(None)
+            }),
+        };
+        report_action(report, &action_url, &path, verb, &result)?;
+        if let Err(e) = result {
+            log::error!("Error syncing {:?}: {}", action, e);
+            failed += 1;
+        }
+    }
+    failures(failed, actions.len())
+}
+
+fn sync_upload(
+    dir: &Path,
+    url: &str,
+    options: &SyncOptions,
+    report: &Reporter,
+    origin: &PelicanInfo,
+    http_client: &reqwest::blocking::Client,
+) -> Result<(), Box<dyn Error>> {
+    let url = url.trim_end_matches('/');
+    let origin_url = get_origin_url(url, origin)?;
+    let mut local = local_files(dir, false)?;
+    let mut remote = remote_files(http_client, &origin_url, true)?;
+    if options.compares_checksums() {
+        add_checksums(dir, &mut local, &mut remote, http_client, &origin_url)?;
+    }
+
+    let actions = plan(&local, &remote, true, options);
+    if options.dry_run {
+        print_plan(&actions, true, report)?;
+        return Ok(());
+    }
+
+    let copies: Vec<String> = actions
+        .iter()
+        .filter_map(|a| match a {
+            Action::Copy(name) => Some(name.clone()),
+            _ => None,
+        })
+        .collect();
+    if !copies.is_empty() {
+        object::make_collections_for(url, &copies, origin, http_client)?;
+    }
+
+    let mut failed = 0;
+    for action in actions.iter() {
+        let (name, verb, mode) = match action {
+            Action::Copy(name) => (name, "put", Verb::Put),
+            Action::Delete(name) => (name, "delete", Verb::Delete),
+        };
+        log::info!("{} {}", verb, name);
+        let action_url = format!("{}/{}", url, webdav::encode_path(name));
+        let path = dir.join(name).to_string_lossy().into_owned();
+        let filename = match mode {
+            Verb::Put => path.clone(),
+            _ => String::new(),
+        };
+        let result = Transfer::new(action_url.clone(), filename, mode)
+            .execute_with_client(origin, http_client)
+            .map(Some);
+        report_action(report, &action_url, &path, verb, &result)?;
+        if let Err(e) = result {
+            log::error!("Error syncing {:?}: {}", action, e);
+            failed += 1;
+        }
+    }
+    failures(failed, actions.len())
+}
+
+/// Make `dst` match `src`, where exactly one of them is a Pelican url.
+pub fn sync(
+    creds: &Credentials,
+    src: &str,
+    dst: &str,
+    options: &SyncOptions,
+    report: &Reporter,
+) -> Result<(), Box<dyn Error>> {
+    if options.checksum && options.no_local_reads {
+        log::warn!("dry run compares sizes and mtimes, since checksums need the local files read");
+    }
+    match (pelican::is_pelican_url(src), pelican::is_pelican_url(dst)) {
+        (true, false) => {
+            let origin = PelicanInfo::from_url(src)?;
+            let cred = creds.get_cred_for_url(src, &["storage.read"], &origin)?;
+            let http_client = build_http_client(cred)?;
+            sync_download(src, Path::new(dst), options, report, &origin, &http_client)
+        }
+        (false, true) => {
+            let origin = PelicanInfo::from_url(dst)?;
+            let write_scopes: &[&str] = if options.delete {
+                &["storage.modify"]
+            } else {
+                &["storage.create", "storage.modify"]
+            };
+            // listing the destination needs read access as well
+            let cred = creds
+                .get_cred_for_urls(&[(dst, &["storage.read"]), (dst, write_scopes)], &origin)?;
+            let http_client = build_http_client(cred)?;
+            sync_upload(Path::new(src), dst, options, report, &origin, &http_client)
+        }
+        _ => Err(Box::new(MyError::Transfer(
+            "sync needs one local path and one Pelican url".into(),
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use httpmock::prelude::*;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::logging::{test_cred, test_logger};
+
+    fn info(size: u64, mtime: u64) -> FileInfo {
+        FileInfo {
+            size: Some(size),
+            mtime: Some(mtime),
+            checksum: None,
+        }
+    }
+
+    #[test]
+    fn test_adler32() {
+        let tmp_dir = TempDir::new().unwrap();
+        let path = tmp_dir.path().join("data");
+        fs::write(&path, "Wikipedia").unwrap();
+        assert_eq!(adler32(&path).unwrap(), "11e60398");
+    }
+
+    #[test]
+    fn test_needs_copy() {
+        assert!(needs_copy(&info(1, 10), None, false, false));
+        assert!(needs_copy(&info(1, 10), Some(&info(2, 10)), false, false));
+        assert!(!needs_copy(&info(1, 10), Some(&info(1, 10)), false, false));
+        assert!(needs_copy(&info(1, 10), Some(&info(1, 20)), false, false));
+        // uploads only care about newer local files
+        assert!(!needs_copy(&info(1, 10), Some(&info(1, 20)), true, false));
+        assert!(needs_copy(&info(1, 30), Some(&info(1, 20)), true, false));
+
+        let mut a = info(1, 10);
+        let mut b = info(1, 20);
+        a.checksum = Some("0A0B0C0D".into());
+        b.checksum = Some("0a0b0c0d".into());
+        assert!(!needs_copy(&a, Some(&b), false, true));
+        b.checksum = None;
+        assert!(needs_copy(&a, Some(&b), false, true));
+    }
+
+    #[test]
+    fn test_plan() {
+        let src = BTreeMap::from([
+            ("a".to_string(), info(1, 10)),
+            ("b".to_string(), info(1, 10)),
+        ]);
+        let dst = BTreeMap::from([
+            ("a".to_string(), info(1, 10)),
+            ("c".to_string(), info(1, 10)),
+        ]);
+        let mut options = SyncOptions {
+            checksum: false,
+            delete: false,
+            dry_run: false,
+            no_local_reads: false,
+        };
+        assert_eq!(
+            plan(&src, &dst, false, &options),
+            vec![Action::Copy("b".into())]
+        );
+        options.delete = true;
+        assert_eq!(
+            plan(&src, &dst, false, &options),
+            vec![Action::Copy("b".into()), Action::Delete("c".into())]
+        );
+    }
+
+    #[test]
+    fn test_sync_download() {
+        test_logger();
+
+        let tmp_dir = TempDir::new().unwrap();
+        let dir = tmp_dir.path();
+        fs::write(dir.join("same.txt"), "same").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(dir.join("same.txt"))
+            .unwrap()
+            .set_modified(httpdate::parse_http_date("Mon, 02 Jun 2025 10:00:00 GMT").unwrap())
+            .unwrap();
+        fs::write(dir.join("extra.txt"), "extra").unwrap();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.is_true(|req| req.method_str() == "PROPFIND")
+                .path("/ns/dir");
+            then.status(207).body(
+                r#"<?xml version="1.0"?><D:multistatus xmlns:D="DAV:">
+<D:response><D:href>/ns/dir/</D:href><D:propstat><D:prop><D:resourcetype><D:collection/></D:resourcetype></D:prop></D:propstat></D:response>
+<D:response><D:href>/ns/dir/same.txt</D:href><D:propstat><D:prop><D:resourcetype/><D:getcontentlength>4</D:getcontentlength><D:getlastmodified>Mon, 02 Jun 2025 10:00:00 GMT</D:getlastmodified></D:prop></D:propstat></D:response>
+<D:response><D:href>/ns/dir/new.txt</D:href><D:propstat><D:prop><D:resourcetype/><D:getcontentlength>3</D:getcontentlength><D:getlastmodified>Mon, 02 Jun 2025 11:00:00 GMT</D:getlastmodified></D:prop></D:propstat></D:response>
+</D:multistatus>"#,
+            );
+        });
+        let same = server.mock(|when, then| {
+            when.method(GET).path("/ns/dir/same.txt");
+            then.status(200).body("same");
+        });
+        let new = server.mock(|when, then| {
+            when.method(GET).path("/ns/dir/new.txt");
+            then.status(200).body("new");
+        });
+
+        let cred = test_cred(&["storage.read:/"]);
+        let client = build_http_client(&cred).unwrap();
+        let origin = PelicanInfo {
+            origins: vec![server.url("/ns")],
+            osdf_prefix: "osdf:///ns".into(),
+        };
+
+        let report = Reporter::default();
+        let mut options = SyncOptions {
+            checksum: false,
+            delete: true,
+            dry_run: true,
+            no_local_reads: false,
+        };
+        sync_download("osdf:///ns/dir", dir, &options, &report, &origin, &client).unwrap();
+        new.assert_calls(0);
+        assert!(dir.join("extra.txt").exists());
+
+        // sync's own dry run plans with checksums, like the real run
+        let head = server.mock(|when, then| {
+            when.method(httpmock::Method::HEAD);
+            then.status(200);
+        });
+        options.checksum = true;
+        sync_download("osdf:///ns/dir", dir, &options, &report, &origin, &client).unwrap();
+        head.assert_calls(1);
+        // but the global --dry-run doesn't read local files
+        options.no_local_reads = true;
+        sync_download("osdf:///ns/dir", dir, &options, &report, &origin, &client).unwrap();
+        head.assert_calls(1);
+        options.checksum = false;
+        options.no_local_reads = false;
+
+        options.dry_run = false;
+        sync_download("osdf:///ns/dir", dir, &options, &report, &origin, &client).unwrap();
+        same.assert_calls(0);
+        new.assert();
+        assert_eq!(fs::read_to_string(dir.join("new.txt")).unwrap(), "new");
+        assert!(!dir.join("extra.txt").exists());
+
+        // now everything is in sync
+        sync_download("osdf:///ns/dir", dir, &options, &report, &origin, &client).unwrap();
+        new.assert_calls(1);
+
+        // a missing source is an error, not an empty listing to delete for
+        server.mock(|when, then| {
+            when.is_true(|req| req.method_str() == "PROPFIND")
+                .path("/ns/typo");
+            then.status(404);
+        });
+        assert!(
+            sync_download("osdf:///ns/typo", dir, &options, &report, &origin, &client).is_err()
+        );
+        assert!(dir.join("new.txt").exists());
+    }
+
+    #[test]
+    fn test_sync_upload_missing_source() {
+        test_logger();
+
+        let tmp_dir = TempDir::new().unwrap();
+        let server = MockServer::start();
+        let any = server.mock(|_, then| {
+            then.status(207);
+        });
+        let client = reqwest::blocking::Client::new();
+        let origin = PelicanInfo {
+            origins: vec![server.url("/ns")],
+            osdf_prefix: "osdf:///ns".into(),
+        };
+        let options = SyncOptions {
+            checksum: false,
+            delete: true,
+            dry_run: false,
+            no_local_reads: false,
+        };
+        let err = sync_upload(
+            &tmp_dir.path().join("typo"),
+            "osdf:///ns/dir",
+            &options,
+            &Reporter::default(),
+            &origin,
+            &client,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("does not exist"));
+        any.assert_calls(0);
+    }
+}
@@ -0,0 +1,407 @@
+use std::error::Error;
+use std::fs;
+use std::os::unix::fs::DirBuilderExt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use serde_json::{Map, Value};
+
+use crate::config;
+use crate::credentials::{Credential, Credentials, NO_EXPIRY};
+use crate::error::MyError;
+use crate::oauth::{self, OAuthClient};
+use crate::output::Reporter;
+use crate::pelican::{NamespaceInfo, PelicanInfo};
+use crate::transfer::{Transfer, Verb};
+
+/// Decode the claims of a JWT, without checking its signature.
+pub fn decode_jwt_claims(token: &str) -> Result<Map<String, Value>, Box<dyn Error>> {
+    let payload = match token.split('.').collect::<Vec<_>>().as_slice() {
+        [_, payload, _] => payload.trim_end_matches('='),
+        _ => {
+            return Err(Box::new(MyError::Credentials(
+                "access token is not a JWT".into(),
+            )));
+        }
+    };
+    let data = URL_SAFE_NO_PAD.decode(payload)?;
+    match serde_json::from_slice(&data)? {
+        Value::Object(claims) => Ok(claims),
+        _ => Err(Box::new(MyError::Credentials(
+            "JWT claims are not an object".into(),
+        ))),
+    }
+}
+
+fn claim_str(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Array(a) => a.iter().map(claim_str).collect::<Vec<_>>().join(", "),
+        other => other.to_string(),
+    }
+}
+
+/// Like `1h 2m 3s`.
+pub fn format_duration(secs: u64) -> String {
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if h > 0 {
+        format!("{}h {}m {}s", h, m, s)
+    } else if m > 0 {
+        format!("{}m {}s", m, s)
+    } else {
+        format!("{}s", s)
+    }
+}
+
+/// When a credential expires, in unix seconds, as credential selection sees
+/// it. None if it doesn't expire.
+pub fn expires_at(cred: &Credential) -> Option<i64> {
+    if cred.expires_at == NO_EXPIRY {
+        return None;
+    }
+    Some(cred.expires_at as i64)
+}
+
+fn fmt_unix_time(secs: i64) -> String {
+    httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64))
+}
+
+/// Labelled lines describing a credential, as of `now` (unix seconds).
+/// The scope and expiry are the ones credential selection uses; the JWT's
+/// own claims follow.
+fn describe(cred: &Credential, now: i64) -> Vec<(&'static str, String)> {
+    let mut ret = vec![
+        ("type", cred.token_type.clone()),
+        ("scope", cred.scope.join(" ")),
+    ];
+    match expires_at(cred) {
+        Some(exp) => {
+            ret.push(("expires", fmt_unix_time(exp)));
+            let time_left = if exp > now {
+                format_duration((exp - now) as u64)
+            } else {
+                format!("expired {} ago", format_duration((now - exp) as u64))
+            };
+            ret.push(("time left", time_left));
+        }
+        None => ret.push(("expires", "never".into())),
+    }
+
+    let claims = match decode_jwt_claims(&cred.access_token) {
+        Ok(claims) => claims,
+        Err(e) => {
+            ret.push(("claims", format!("unavailable ({})", e)));
+            return ret;
+        }
+    };
+    for (label, claim) in [
+        ("issuer", "iss"),
+        ("subject", "sub"),
+        ("audience", "aud"),
+        ("jwt scope", "scope"),
+        ("wlcg.ver", "wlcg.ver"),
+    ] {
+        if let Some(value) = claims.get(claim) {
+            ret.push((label, claim_str(value)));
+        }
+    }
+    if let Some(exp) = claims.get("exp").and_then(|e| e.as_i64()) {
+        ret.push(("jwt exp", fmt_unix_time(exp)));
+    }
+    ret
+}
+
+/// Print each credential, and which one would be used for `url`, if given.
+pub fn inspect(
+    creds: &Credentials,
+    url: Option<&str>,
+    verb: Verb,
+    report: &Reporter,
+) -> Result<(), Box<dyn Error>> {
+    let choice = match url {
+        Some(url) => {
+            let info = PelicanInfo::from_url(url)?;
+            let transfer = Transfer::new(url.to_string(), String::new(), verb);
+            Some(creds.explain_correct_cred(&transfer, &info)?)
+        }
+        None => None,
+    };
+
+    if let Some(url) = url
+        && !report.is_json()
+    {
+        println!("credentials to {:?} {}", verb, url);
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let mut count = 0;
+    for (i, cred) in creds.iter().enumerate() {
+        count += 1;
+        let reason = choice.as_ref().map(|c| c.reasons[i].clone());
+        if report.is_json() {
+            let claims = decode_jwt_claims(&cred.access_token).unwrap_or_default();
+            let exp = expires_at(cred);
+            report.json(&serde_json::json!({
+                "source": cred.source,
+                "token_type": cred.token_type,
+                "scope": cred.scope,
+                "claims": claims,
+                "expires": exp,
+                "seconds_left": exp.map(|exp| exp - now),
+                "url": url,
+                "reason": reason,
+            }))?;
+            continue;
+        }
+        println!(
+            "credential {}: {}",
+            i + 1,
+            cred.source.as_deref().unwrap_or("<unknown source>")
+        );
+        for (label, value) in describe(cred, now) {
+            println!("  {:<10} {}", format!("{}:", label), value);
+        }
+        if let Some(reason) = reason {
+            println!("  {:<10} {}", "for url:", reason);
+        }
+    }
+    if count == 0 && !report.is_json() {
+        println!("no credentials found");
+    }
+    if let Some(choice) = choice
+        && choice.selected.is_none()
+    {
+        if let Some(url) = url
+            && let Ok(info) = NamespaceInfo::from_url(url)
+        {
+            for issuer in info.issuers {
+                log::warn!(
+                    "get a token with: token get --issuer {} --scope <scope> --client-id <id>",
+                    issuer
+                );
+            }
+        }
+        return Err(Box::new(MyError::Credentials(
+            "No matching credentials for url".into(),
+        )));
+    }
+    Ok(())
+}
+
+/// Pick the issuer the director names for a namespace.
+fn choose_issuer(url: &str, issuers: &[String]) -> Result<String, Box<dyn Error>> {
+    match issuers {
+        [issuer] => Ok(issuer.clone()),
+        [] => Err(Box::new(MyError::Credentials(format!(
+            "the director names no token issuer for {}, give one with --issuer",
+            url
+        )))),
+        _ => Err(Box::new(MyError::Credentials(format!(
+            "the director names several token issuers for {}, pick one with --issuer: {}",
+            url,
+            issuers.join(" ")
+        )))),
+    }
+}
+
+/// The name of the file a token from `issuer` with these scopes is saved
+/// to, so tokens for different scopes don't replace each other.
+fn token_file_name(issuer: &str, scopes: &[String]) -> String {
+    let mut scopes = scopes.to_vec();
+    scopes.sort();
+    scopes.dedup();
+    let name: String = std::iter::once(
+        issuer
+            .trim_start_matches("https://")
+            .trim_start_matches("http://")
+            .trim_end_matches('/'),
+    )
+    .chain(scopes.iter().map(|s| s.trim_end_matches('/')))
+    .collect::<Vec<_>>()
+    .join("_")
+    .chars()
+    .map(|c| {
+        if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+            c
+        } else {
+            '_'
+        }
+    })
+    .collect();
+    format!("{}.use", name)
+}
+
+/// Get a token with the OAuth2 device flow, and save it to the user token
+/// dir, where credential discovery finds it. Without an `issuer`, use the
+/// one the director names for the namespace of `url`.
+pub fn get(
+    issuer: Option<&str>,
+    url: Option<&str>,
+    scopes: &[String],
+    oauth_client: &OAuthClient,
+    report: &Reporter,
+) -> Result<(), Box<dyn Error>> {
+    let issuer = match (issuer, url) {
+        (Some(issuer), _) => issuer.to_string(),
+        (None, Some(url)) => choose_issuer(url, &NamespaceInfo::from_url(url)?.issuers)?,
+        (None, None) => {
+            return Err(Box::new(MyError::Credentials(
+                "give an --issuer or a url to find it from".into(),
+            )));
+        }
+    };
+    let dir = config::user_token_dir().ok_or_else(|| {
+        MyError::Credentials("cannot find the user token dir, set HOME or XDG_DATA_HOME".into())
+    })?;
+
+    let cred = oauth::device_flow(
+        &oauth::http_client(),
+        &issuer,
+        oauth_client,
+        &scopes.join(" "),
+        |device| {
+            match &device.verification_uri_complete {
+                Some(uri) => eprintln!("To approve this token, go to {}", uri),
+                None => eprintln!(
+                    "To approve this token, go to {} and enter the code {}",
+                    device.verification_uri, device.user_code
+                ),
+            }
+            eprintln!("Waiting for approval...");
+        },
+    )?;
+
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)?;
+    let path = dir.join(token_file_name(&issuer, scopes));
+    let path = path.to_str().ok_or_else(|| {
+        MyError::Credentials(format!("token path {} is not utf-8", path.display()))
+    })?;
+    oauth::write_atomically(path, &cred)?;
+
+    if report.is_json() {
+        report.json(&serde_json::json!({
+            "issuer": issuer,
+            "path": path,
+            "scope": cred.scope,
+            "expires": expires_at(&cred),
+        }))?;
+    } else {
+        println!("saved token to {}", path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::make_jwt;
+
+    #[test]
+    fn test_decode_jwt_claims() {
+        let token = make_jwt(r#"{"iss":"https://issuer","exp":1000,"wlcg.ver":"1.0"}"#);
+        let claims = decode_jwt_claims(&token).unwrap();
+        assert_eq!(claims["iss"], "https://issuer");
+        assert_eq!(claims["exp"], 1000);
+
+        assert!(decode_jwt_claims("not-a-jwt").is_err());
+        assert!(decode_jwt_claims("a.!!!.c").is_err());
+    }
+
+    #[test]
+    fn test_choose_issuer() {
+        let url = "osdf:///icecube/file";
+        assert_eq!(
+            choose_issuer(url, &["https://issuer-a".into()]).unwrap(),
+            "https://issuer-a"
+        );
+        assert!(choose_issuer(url, &[]).is_err());
+        let err = choose_issuer(url, &["https://issuer-a".into(), "https://issuer-b".into()])
+            .unwrap_err();
+        assert!(err.to_string().contains("https://issuer-b"));
+    }
+
+    #[test]
+    fn test_token_file_name() {
+        assert_eq!(
+            token_file_name(
+                "https://token-issuer.example/icecube/",
+                &["storage.read:/icecube".into()]
+            ),
+            "token-issuer.example_icecube_storage.read__icecube.use"
+        );
+        assert_eq!(
+            token_file_name("https://issuer:8443", &[]),
+            "issuer_8443.use"
+        );
+        // the same scopes in any order share a file, other scopes don't
+        let scopes = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            token_file_name(
+                "https://issuer",
+                &scopes(&["offline_access", "storage.read:/"])
+            ),
+            token_file_name(
+                "https://issuer",
+                &scopes(&["storage.read:/", "offline_access"])
+            )
+        );
+        assert_ne!(
+            token_file_name("https://issuer", &scopes(&["storage.read:/a"])),
+            token_file_name("https://issuer", &scopes(&["storage.read:/b"]))
+        );
+    }
+
+    #[test]
+    fn test_describe() {
+        let cred = Credential {
+            access_token: make_jwt(
+                r#"{"iss":"https://issuer","sub":"me","aud":["a","b"],"exp":5000,"scope":"storage.read:/ storage.create:/out","wlcg.ver":"1.0"}"#,
+            ),
+            token_type: "bearer".into(),
+            expires_in: 3600,
+            expires_at: 5000.,
+            scope: vec!["storage.read:/".into(), "storage.create:/out".into()],
+            source: None,
+            ..Default::default()
+        };
+        let lines = describe(&cred, 5000 - 3723);
+        assert!(lines.contains(&("issuer", "https://issuer".into())));
+        assert!(lines.contains(&("audience", "a, b".into())));
+        assert!(lines.contains(&("scope", "storage.read:/ storage.create:/out".into())));
+        assert!(lines.contains(&("wlcg.ver", "1.0".into())));
+        assert!(lines.contains(&("time left", "1h 2m 3s".into())));
+
+        // the file's values are the ones selection uses, so they come
+        // first, with the JWT's as extra lines
+        let cred = Credential {
+            expires_at: 4000.,
+            scope: vec!["storage.read:/narrow".into()],
+            ..cred
+        };
+        let lines = describe(&cred, 5000 - 3723);
+        assert!(lines.contains(&("scope", "storage.read:/narrow".into())));
+        assert!(lines.contains(&("jwt scope", "storage.read:/ storage.create:/out".into())));
+        assert!(lines.contains(&("time left", "45m 23s".into())));
+        assert!(lines.contains(&("jwt exp", fmt_unix_time(5000))));
+
+        // not a JWT
+        let cred = Credential {
+            access_token: "opaque".into(),
+            scope: vec!["storage.read:/".into()],
+            expires_at: 100.,
+            ..cred
+        };
+        let lines = describe(&cred, 160);
+        assert!(lines.contains(&("scope", "storage.read:/".into())));
+        assert!(lines.contains(&("time left", "expired 1m 0s ago".into())));
+
+        let cred = Credential {
+            expires_at: NO_EXPIRY,
+            ..cred
+        };
+        assert!(describe(&cred, 160).contains(&("expires", "never".into())));
+    }
+}
@@ -1,9 +1,14 @@
 use std::error::Error;
-use std::time::Duration;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use reqwest::Method;
 use reqwest::blocking::RequestBuilder;
 
-use crate::credentials::Credentials;
+use crate::config;
+use crate::credentials::{Credential, Credentials};
 use crate::error::MyError;
 use crate::pelican::PelicanInfo;
 
@@ -15,9 +20,147 @@ fn url_join(a: &str, b: &str) -> String {
     }
 }
 
+/// Translate an OSDF url into the same object at a randomly chosen origin.
+pub(crate) fn get_origin_url(url: &str, origin: &PelicanInfo) -> Result<String, Box<dyn Error>> {
+    Ok(get_origin_urls(&[url], origin)?.remove(0))
+}
+
+/// Translate several OSDF urls in one namespace, using the same origin for all.
+pub(crate) fn get_origin_urls(
+    urls: &[&str],
+    origin: &PelicanInfo,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    Ok(choose_origin_urls(urls, origin)?.1)
+}
+
+/// Like `get_origin_urls`, also returning the origin that was chosen.
+fn choose_origin_urls(
+    urls: &[&str],
+    origin: &PelicanInfo,
+) -> Result<(String, Vec<String>), Box<dyn Error>> {
+    let origin_url = origin.choose_origin()?;
+    log::debug!("chosen origin: {}", origin_url);
+    let prefix = origin.get_osdf_prefix();
+    log::debug!("osdf prefix: {}", prefix);
+    let mut ret = Vec::new();
+    for url in urls.iter() {
+        match url.split_once(prefix) {
+            Some((_, suffix)) => ret.push(url_join(origin_url, suffix)),
+            None => {
+                return Err(Box::new(MyError::Transfer(
+                    "url does not match OSDF prefix".into(),
+                )));
+            }
+        }
+    }
+    Ok((origin_url.to_string(), ret))
+}
+
+/// Build an HTTP client that sends the credential's token with every request.
+pub(crate) fn build_http_client(
+    cred: &Credential,
+) -> Result<reqwest::blocking::Client, Box<dyn Error>> {
+    let mut headers = reqwest::header::HeaderMap::new();
+    let mut auth_value =
+        reqwest::header::HeaderValue::from_str(format!("Bearer {}", cred.access_token).as_str())?;
+    auth_value.set_sensitive(true);
+    headers.insert(reqwest::header::AUTHORIZATION, auth_value);
+
+    Ok(reqwest::blocking::ClientBuilder::new()
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(Duration::from_secs(config::get().timeout.value))
+        .connect_timeout(Duration::from_secs(config::get().connect_timeout.value))
+        .default_headers(headers)
+        .build()
+        .expect("HTTP Client should build"))
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
 pub(crate) enum Verb {
     Put,
     Get,
+    /// Remove an object or empty collection
+    Delete,
+    /// Create a collection
+    Mkcol,
+}
+
+/// Client errors won't go away by asking again, except for timeouts and
+/// rate limits, and a used up stream can't be sent again.
+fn is_retryable(e: &(dyn Error + 'static)) -> bool {
+    match e.downcast_ref::<MyError>() {
+        Some(MyError::Http {
+            status: Some(408 | 429),
+            ..
+        }) => true,
+        Some(MyError::Http {
+            status: Some(400..=499),
+            ..
+        })
+        | Some(MyError::Stream(_)) => false,
+        _ => true,
+    }
+}
+
+/// The filename for stdin when uploading, or stdout when downloading.
+pub(crate) const STDIO_FILENAME: &str = "-";
+
+/// Counts the bytes read, so a stream of unknown length can report its size.
+struct CountingReader<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+/// A body from a reader of unknown length, sent with chunked transfer encoding.
+fn stream_body<R: Read + Send + 'static>(reader: R) -> (reqwest::blocking::Body, Arc<AtomicU64>) {
+    let count = Arc::new(AtomicU64::new(0));
+    let body = reqwest::blocking::Body::new(CountingReader {
+        inner: reader,
+        count: count.clone(),
+    });
+    (body, count)
+}
+
+/// What a transfer would do, without doing it.
+#[derive(serde::Serialize, Debug, PartialEq, Clone)]
+pub(crate) struct TransferPlan {
+    pub url: String,
+    pub verb: String,
+    pub origin: String,
+    pub origin_url: String,
+    /// The file the credential came from, if known
+    pub credential: Option<String>,
+    pub scopes: Vec<String>,
+}
+
+/// Statistics about a completed transfer.
+#[derive(serde::Serialize, Debug, PartialEq, Clone)]
+pub(crate) struct TransferResult {
+    pub url: String,
+    /// The object url at the origin that was used
+    pub origin_url: String,
+    pub attempts: u32,
+    pub bytes: u64,
+    /// Unix timestamps, in seconds
+    pub start_time: f64,
+    pub end_time: f64,
+    /// Seconds from sending the successful request to getting a response;
+    /// not set for uploads, where the response only comes after the body
+    pub time_to_first_byte: Option<f64>,
+    /// Total seconds, including failed attempts
+    pub duration: f64,
+}
+
+fn unix_time(t: SystemTime) -> f64 {
+    t.duration_since(UNIX_EPOCH).unwrap().as_secs_f64()
 }
 
 pub(crate) struct Transfer {
@@ -35,114 +178,213 @@ impl Transfer {
         }
     }
 
-    fn get_origin_url(&self, origin: &PelicanInfo) -> Result<String, Box<dyn Error>> {
-        let origin_url = origin.choose_origin()?;
-        log::debug!("chosen origin: {}", origin_url);
-        let prefix = origin.get_osdf_prefix();
-        log::debug!("osdf prefix: {}", prefix);
-        match self.url.split_once(prefix) {
-            Some((_, suffix)) => Ok(url_join(origin_url, suffix)),
-            None => Err(Box::new(MyError::Transfer(
-                "url does not match OSDF prefix".into(),
-            ))),
-        }
+    /// Uploads from stdin can only be sent once.
+    fn reads_stdin(&self) -> bool {
+        self.mode == Verb::Put && self.filename == STDIO_FILENAME
+    }
+
+    /// Downloads to stdout, so nothing else should be printed there.
+    pub fn writes_stdout(&self) -> bool {
+        self.mode == Verb::Get && self.filename == STDIO_FILENAME
+    }
+
+    /// The chosen origin, and the url of the object there.
+    fn get_origin_url(&self, origin: &PelicanInfo) -> Result<(String, String), Box<dyn Error>> {
+        let (chosen, mut urls) = choose_origin_urls(&[&self.url], origin)?;
+        Ok((chosen, urls.remove(0)))
     }
 
     fn do_transfer(
         &self,
         origin: &PelicanInfo,
         http_client: &reqwest::blocking::Client,
-    ) -> Result<(), Box<dyn Error>> {
-        let final_url = self.get_origin_url(origin)?;
+    ) -> Result<TransferResult, Box<dyn Error>> {
+        let (_, final_url) = self.get_origin_url(origin)?;
         log::info!("using final url {}", final_url);
+        let timeout = config::get().settings_for(&self.url).timeout;
 
-        let send = |x: RequestBuilder| match x.send() {
+        let send = |x: RequestBuilder| match x.timeout(timeout).send() {
             Ok(x) => Ok(x),
-            Err(e) => Err(Box::new(MyError::Transfer(format!(
-                "Error sending the request: {:?}",
-                e
-            )))),
+            Err(e) => Err(Box::new(MyError::Http {
+                url: final_url.clone(),
+                status: None,
+                details: format!("Error sending the request: {:?}", e),
+            })),
         };
 
-        let result = match self.mode {
+        let request_start = Instant::now();
+        let (result, bytes, time_to_first_byte) = match self.mode {
             Verb::Get => {
-                let mut file = std::fs::File::create(&self.filename)?;
-                let mut ret = send(http_client.get(final_url))?;
+                let to_stdout = self.writes_stdout();
+                let mut out: Box<dyn Write> = if to_stdout {
+                    Box::new(std::io::stdout().lock())
+                } else {
+                    Box::new(std::fs::File::create(&self.filename)?)
+                };
+                let mut ret = send(http_client.get(&final_url))?;
+                let time_to_first_byte = Some(request_start.elapsed().as_secs_f64());
                 if !ret.status().is_success() {
-                    return Err(Box::new(MyError::Transfer(format!(
-                        "Error getting file. status {}, body {}",
-                        ret.status(),
-                        ret.text().unwrap_or("<no_body>".into())
-                    ))));
+                    return Err(Box::new(MyError::Http {
+                        url: final_url.clone(),
+                        status: Some(ret.status().as_u16()),
+                        details: format!(
+                            "Error getting file. body {}",
+                            ret.text().unwrap_or("<no_body>".into())
+                        ),
+                    }));
                 }
-                ret.copy_to(&mut file)?;
-                ret
+                let mut copy = || -> Result<u64, Box<dyn Error>> {
+                    let bytes = ret.copy_to(&mut out)?;
+                    out.flush()?;
+                    Ok(bytes)
+                };
+                let bytes = match copy() {
+                    Ok(bytes) => bytes,
+                    // whatever already went to stdout can't be taken back
+                    Err(e) if to_stdout => {
+                        return Err(Box::new(MyError::Stream(format!(
+                            "Error writing {} to stdout: {}",
+                            final_url, e
+                        ))));
+                    }
+                    Err(e) => return Err(e),
+                };
+                (ret, bytes, time_to_first_byte)
+            }
+            Verb::Put if self.reads_stdin() => {
+                let (body, count) = stream_body(std::io::stdin());
+                let ret = send(http_client.put(&final_url).body(body))?;
+                (ret, count.load(Ordering::Relaxed), None)
             }
             Verb::Put => {
                 let file = std::fs::File::open(&self.filename)?;
-                send(http_client.put(final_url).body(file))?
+                let bytes = file.metadata()?.len();
+                let ret = send(http_client.put(&final_url).body(file))?;
+                (ret, bytes, None)
+            }
+            Verb::Delete => {
+                let ret = send(http_client.delete(&final_url))?;
+                (ret, 0, Some(request_start.elapsed().as_secs_f64()))
+            }
+            Verb::Mkcol => {
+                let ret = send(http_client.request(Method::from_bytes(b"MKCOL")?, &final_url))?;
+                (ret, 0, Some(request_start.elapsed().as_secs_f64()))
             }
         };
 
         // Verify response
         if !result.status().is_success() {
-            return Err(Box::new(MyError::Transfer(format!(
-                "Error transferring file. status {}, body {}",
-                result.status(),
-                result.text().unwrap_or("<no_body>".into())
-            ))));
+            return Err(Box::new(MyError::Http {
+                url: final_url.clone(),
+                status: Some(result.status().as_u16()),
+                details: format!(
+                    "Error transferring file. body {}",
+                    result.text().unwrap_or("<no_body>".into())
+                ),
+            }));
         }
 
-        Ok(())
+        Ok(TransferResult {
+            url: self.url.clone(),
+            origin_url: final_url,
+            attempts: 1,
+            bytes,
+            start_time: 0.,
+            end_time: 0.,
+            time_to_first_byte,
+            duration: 0.,
+        })
+    }
+
+    /// Pick the credential and origin url, but send nothing and touch no local file.
+    pub fn plan(
+        &self,
+        creds: &Credentials,
+        origin: &PelicanInfo,
+    ) -> Result<TransferPlan, Box<dyn Error>> {
+        self.plan_with_cred(creds.get_correct_cred(self, origin)?, origin)
     }
 
-    pub fn execute(&self, creds: &Credentials, origin: &PelicanInfo) -> Result<(), Box<dyn Error>> {
+    /// Like `plan`, for callers that already picked a credential.
+    pub fn plan_with_cred(
+        &self,
+        cred: &Credential,
+        origin: &PelicanInfo,
+    ) -> Result<TransferPlan, Box<dyn Error>> {
+        let (chosen, origin_url) = self.get_origin_url(origin)?;
+        Ok(TransferPlan {
+            url: self.url.clone(),
+            verb: format!("{:?}", self.mode).to_lowercase(),
+            origin: chosen,
+            origin_url,
+            credential: cred.source.clone(),
+            scopes: cred.scope.clone(),
+        })
+    }
+
+    pub fn execute(
+        &self,
+        creds: &Credentials,
+        origin: &PelicanInfo,
+    ) -> Result<TransferResult, Box<dyn Error>> {
         let cred = creds.get_correct_cred(self, origin)?;
+        let http_client = build_http_client(cred)?;
+        self.execute_with_client(origin, &http_client)
+    }
 
-        let mut headers = reqwest::header::HeaderMap::new();
-        let mut auth_value = reqwest::header::HeaderValue::from_str(
-            format!("Bearer {}", cred.access_token).as_str(),
-        )?;
-        auth_value.set_sensitive(true);
-        headers.insert(reqwest::header::AUTHORIZATION, auth_value);
-
-        let http_client = reqwest::blocking::ClientBuilder::new()
-            .redirect(reqwest::redirect::Policy::none())
-            .timeout(Duration::from_secs(3600))
-            .connect_timeout(Duration::from_secs(60))
-            .default_headers(headers)
-            .build()
-            .expect("HTTP Client should build");
-
-        for retries in 0..5 {
+    /// Execute with a client from `build_http_client`, for callers that
+    /// already picked a credential covering this url.
+    pub fn execute_with_client(
+        &self,
+        origin: &PelicanInfo,
+        http_client: &reqwest::blocking::Client,
+    ) -> Result<TransferResult, Box<dyn Error>> {
+        let start_time = SystemTime::now();
+        let start = Instant::now();
+        let max_retries = config::get().settings_for(&self.url).retries;
+        let mut retries = 0;
+        loop {
             log::info!("Sending request. Retry count={}", retries);
-            match self.do_transfer(origin, &http_client) {
-                Ok(_) => return Ok(()),
+            match self.do_transfer(origin, http_client) {
+                Ok(mut result) => {
+                    result.attempts = retries + 1;
+                    result.start_time = unix_time(start_time);
+                    result.end_time = unix_time(SystemTime::now());
+                    result.duration = start.elapsed().as_secs_f64();
+                    log::info!("transfer stats: {:?}", result);
+                    return Ok(result);
+                }
+                Err(e) if self.reads_stdin() && is_retryable(e.as_ref()) => {
+                    return Err(Box::new(MyError::Stream(format!(
+                        "upload from stdin failed and can't be resent: {}",
+                        e
+                    ))));
+                }
                 Err(e) => {
-                    if retries > 4 {
+                    if retries >= max_retries || !is_retryable(e.as_ref()) {
                         return Err(e);
                     } else {
                         log::warn!("Error in transfer (retry count {}): {:?}", retries, e);
                     }
                 }
             }
+            retries += 1;
         }
-        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{
-        io::{Read, Write},
-        time::{SystemTime, UNIX_EPOCH},
-    };
+    use std::io::{Read, Write};
 
     use httpmock::prelude::*;
     use tempfile::NamedTempFile;
 
     use super::*;
-    use crate::{credentials::Credential, logging::test_logger, transfer::Verb};
+    use crate::{
+        logging::{test_cred, test_logger},
+        transfer::Verb,
+    };
 
     #[test]
     fn test_get_origin_url() {
@@ -160,7 +402,13 @@ mod tests {
         };
 
         let ret = transfer.get_origin_url(&info).unwrap();
-        assert_eq!(ret, "http://origin/read/scope/file.bin");
+        assert_eq!(
+            ret,
+            (
+                "http://origin".into(),
+                "http://origin/read/scope/file.bin".into()
+            )
+        );
     }
 
     #[test]
@@ -178,7 +426,7 @@ mod tests {
             osdf_prefix: "url://namespace".into(),
         };
 
-        let ret = transfer.get_origin_url(&info).unwrap();
+        let (_, ret) = transfer.get_origin_url(&info).unwrap();
         assert_eq!(ret, "http://origin/read/scope/file.bin");
     }
 
@@ -195,20 +443,7 @@ mod tests {
             then.status(200).body(TEST_DATA);
         });
 
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs_f32();
-        let test_cred = Credential {
-            access_token: "token".into(),
-            token_type: "bearer".into(),
-            expires_in: 3600,
-            expires_at: now + 3600.,
-            scope: vec![
-                "storage.read:/read/scope".into(),
-                "storage.modify:/write/scope".into(),
-            ],
-        };
+        let test_cred = test_cred(&["storage.read:/read/scope", "storage.modify:/write/scope"]);
         let creds = Credentials::new(vec![test_cred.clone()]);
 
         let file_path = NamedTempFile::new().ok().unwrap();
@@ -222,15 +457,119 @@ mod tests {
             osdf_prefix: "url://namespace".into(),
         };
 
-        transfer.execute(&creds, &info).unwrap();
+        let result = transfer.execute(&creds, &info).unwrap();
 
         mock.assert();
+        assert_eq!(result.attempts, 1);
+        assert_eq!(result.bytes, TEST_DATA.len() as u64);
+        assert_eq!(result.origin_url, server.url("/read/scope/file.bin"));
+        assert!(result.end_time >= result.start_time);
         assert!(file_path.path().exists());
         let mut data = String::new();
         file_path.as_file().read_to_string(&mut data).unwrap();
         assert_eq!(data, TEST_DATA);
     }
 
+    #[test]
+    fn test_execute_get_error() {
+        test_logger();
+
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.path("/read/scope/file.bin");
+            then.status(500);
+        });
+
+        let test_cred = test_cred(&["storage.read:/read/scope"]);
+        let creds = Credentials::new(vec![test_cred.clone()]);
+
+        let file_path = NamedTempFile::new().ok().unwrap();
+        let transfer = Transfer::new(
+            "url://namespace/read/scope/file.bin".into(),
+            file_path.path().to_str().unwrap().into(),
+            Verb::Get,
+        );
+        let info = PelicanInfo {
+            origins: vec![server.url("/")],
+            osdf_prefix: "url://namespace".into(),
+        };
+
+        assert!(transfer.execute(&creds, &info).is_err());
+        mock.assert_calls(5);
+    }
+
+    #[test]
+    fn test_execute_get_not_found() {
+        test_logger();
+
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.path("/read/scope/file.bin");
+            then.status(404);
+        });
+
+        let test_cred = test_cred(&["storage.read:/read/scope"]);
+        let creds = Credentials::new(vec![test_cred.clone()]);
+
+        let file_path = NamedTempFile::new().ok().unwrap();
+        let transfer = Transfer::new(
+            "url://namespace/read/scope/file.bin".into(),
+            file_path.path().to_str().unwrap().into(),
+            Verb::Get,
+        );
+        let info = PelicanInfo {
+            origins: vec![server.url("/")],
+            osdf_prefix: "url://namespace".into(),
+        };
+
+        assert!(transfer.execute(&creds, &info).is_err());
+        mock.assert_calls(1);
+    }
+
+    #[test]
+    fn test_execute_delete_mkcol() {
+        test_logger();
+
+        let server = MockServer::start();
+        let delete = server.mock(|when, then| {
+            when.method(httpmock::Method::DELETE)
+                .path("/write/scope/file.bin")
+                .header("Authorization", "Bearer token");
+            then.status(204);
+        });
+        let mkcol = server.mock(|when, then| {
+            when.is_true(|req| req.method_str() == "MKCOL")
+                .path("/write/scope/dir")
+                .header("Authorization", "Bearer token");
+            then.status(201);
+        });
+
+        let test_cred = test_cred(&["storage.modify:/write/scope"]);
+        let creds = Credentials::new(vec![test_cred.clone()]);
+        let info = PelicanInfo {
+            origins: vec![server.url("/")],
+            osdf_prefix: "url://namespace".into(),
+        };
+
+        Transfer::new(
+            "url://namespace/write/scope/file.bin".into(),
+            String::new(),
+            Verb::Delete,
+        )
+        .execute(&creds, &info)
+        .unwrap();
+        delete.assert();
+
+        Transfer::new(
+            "url://namespace/write/scope/dir".into(),
+            String::new(),
+            Verb::Mkcol,
+        )
+        .execute(&creds, &info)
+        .unwrap();
+        mkcol.assert();
+    }
+
     #[test]
     fn test_execute_put() {
         test_logger();
@@ -245,20 +584,7 @@ mod tests {
             then.status(200);
         });
 
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs_f32();
-        let test_cred = Credential {
-            access_token: "token".into(),
-            token_type: "bearer".into(),
-            expires_in: 3600,
-            expires_at: now + 3600.,
-            scope: vec![
-                "storage.read:/read/scope".into(),
-                "storage.modify:/write/scope".into(),
-            ],
-        };
+        let test_cred = test_cred(&["storage.read:/read/scope", "storage.modify:/write/scope"]);
         let creds = Credentials::new(vec![test_cred.clone()]);
 
         let file_path = NamedTempFile::new().ok().unwrap();
@@ -274,8 +600,121 @@ mod tests {
             osdf_prefix: "url://namespace/org".into(),
         };
 
-        transfer.execute(&creds, &info).unwrap();
+        let result = transfer.execute(&creds, &info).unwrap();
+
+        mock.assert();
+        assert_eq!(result.bytes, TEST_DATA.len() as u64);
+    }
+
+    #[test]
+    fn test_stream_body_is_chunked() {
+        test_logger();
+
+        const TEST_DATA: &str = "somestreamdata";
+
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(PUT)
+                .path("/out.tar")
+                .header("transfer-encoding", "chunked")
+                .body(TEST_DATA);
+            then.status(201);
+        });
+
+        // a reader that isn't a file, like stdin
+        let reader = std::io::Cursor::new(TEST_DATA.as_bytes().to_vec()).chain(std::io::empty());
+        let (body, count) = stream_body(reader);
+        let resp = reqwest::blocking::Client::new()
+            .put(server.url("/out.tar"))
+            .body(body)
+            .send()
+            .unwrap();
 
         mock.assert();
+        assert_eq!(resp.status().as_u16(), 201);
+        assert_eq!(count.load(Ordering::Relaxed), TEST_DATA.len() as u64);
+    }
+
+    #[test]
+    fn test_stream_errors_not_retryable() {
+        assert!(!is_retryable(&MyError::Stream("stdin".into())));
+        assert!(is_retryable(&MyError::Http {
+            url: "http://origin".into(),
+            status: None,
+            details: "connection reset".into(),
+        }));
+        for (status, retryable) in [
+            (403, false),
+            (404, false),
+            (408, true),
+            (429, true),
+            (503, true),
+        ] {
+            assert_eq!(
+                is_retryable(&MyError::Http {
+                    url: "http://origin".into(),
+                    status: Some(status),
+                    details: String::new(),
+                }),
+                retryable
+            );
+        }
+
+        let transfer = Transfer::new("url://ns/file".into(), "-".into(), Verb::Put);
+        assert!(transfer.reads_stdin());
+        let transfer = Transfer::new("url://ns/file".into(), "-".into(), Verb::Get);
+        assert!(!transfer.reads_stdin());
+    }
+
+    #[test]
+    fn test_plan() {
+        test_logger();
+
+        let test_cred = Credential {
+            expires_at: 0.,
+            source: Some("/creds/write.use".into()),
+            ..test_cred(&["storage.modify:/write/scope"])
+        };
+        let creds = Credentials::new(vec![test_cred]);
+        let info = PelicanInfo {
+            origins: vec!["http://origin/org".into()],
+            osdf_prefix: "url://namespace/org".into(),
+        };
+
+        // the local file doesn't need to exist
+        let transfer = Transfer::new(
+            "url://namespace/org/write/scope/file.bin".into(),
+            "/does/not/exist".into(),
+            Verb::Put,
+        );
+        let plan = transfer.plan(&creds, &info).unwrap();
+        assert_eq!(plan.verb, "put");
+        assert_eq!(plan.origin, "http://origin/org");
+        assert_eq!(plan.origin_url, "http://origin/org/write/scope/file.bin");
+        assert_eq!(plan.credential, Some("/creds/write.use".into()));
+        assert_eq!(plan.scopes, vec!["storage.modify:/write/scope"]);
+
+        let transfer = Transfer::new(
+            "url://namespace/org/write/scope/file.bin".into(),
+            "/does/not/exist".into(),
+            Verb::Get,
+        );
+        assert!(transfer.plan(&creds, &info).is_err());
+
+        // the plan names the origin that was used, even when one is a
+        // prefix of another
+        let info = PelicanInfo {
+            origins: vec!["http://origin".into(), "http://origin:8443".into()],
+            osdf_prefix: "url://namespace".into(),
+        };
+        let transfer = Transfer::new(
+            "url://namespace/write/scope/file.bin".into(),
+            "/does/not/exist".into(),
+            Verb::Put,
+        );
+        for _ in 0..20 {
+            let plan = transfer.plan(&creds, &info).unwrap();
+            assert!(plan.origin_url.starts_with(&format!("{}/", plan.origin)));
+        }
     }
 }
@@ -0,0 +1,562 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use reqwest::Method;
+use reqwest::blocking::Client;
+
+use crate::error::MyError;
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:propfind xmlns:D="DAV:">
+  <D:prop>
+    <D:resourcetype/>
+    <D:getcontentlength/>
+    <D:getlastmodified/>
+  </D:prop>
+</D:propfind>
+"#;
+const DAV_NS: &str = "DAV:";
+const WANT_DIGEST: &str = "adler32, md5, crc32c, sha-256";
+
+/// One resource from a PROPFIND response.
+#[derive(serde::Serialize, Debug, PartialEq, Clone)]
+pub(crate) struct DavEntry {
+    /// Path relative to the requested url. Empty for the url itself.
+    pub name: String,
+    pub size: Option<u64>,
+    pub mtime: Option<String>,
+    pub is_collection: bool,
+}
+
+/// Metadata about a single object.
+#[derive(serde::Serialize, Debug, PartialEq, Clone)]
+pub(crate) struct ObjectStat {
+    pub url: String,
+    pub exists: bool,
+    pub is_collection: bool,
+    pub size: Option<u64>,
+    pub mtime: Option<String>,
+    pub etag: Option<String>,
+    /// Checksums from the `Digest` header, keyed by lowercase algorithm name
+    pub checksums: BTreeMap<String, String>,
+}
+
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut ret = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && i + 2 < bytes.len()
+            && let Some(n) = std::str::from_utf8(&bytes[i + 1..i + 3])
+                .ok()
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+        {
+            ret.push(n);
+            i += 3;
+        } else {
+            ret.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&ret).into_owned()
+}
+
+/// Percent-encode a relative path so it can be appended to a url.
+pub fn encode_path(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                ret.push(b as char)
+            }
+            b => ret.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    ret
+}
+
+/// Get the decoded path of a url or absolute href.
+fn href_path(href: &str) -> String {
+    let path = match href.split_once("://") {
+        Some((_, rest)) => match rest.find('/') {
+            Some(n) => &rest[n..],
+            None => "/",
+        },
+        None => href,
+    };
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    percent_decode(path)
+}
+
+/// Parse a 207 Multi-Status body, naming each entry relative to `base_path`.
+pub fn parse_multistatus(body: &str, base_path: &str) -> Result<Vec<DavEntry>, Box<dyn Error>> {
+    let doc = roxmltree::Document::parse(body)
+        .map_err(|e| MyError::Transfer(format!("Error parsing PROPFIND response: {}", e)))?;
+    let base_path = base_path.trim_end_matches('/');
+
+    let mut ret = Vec::new();
+    for response in doc
+        .descendants()
+        .filter(|n| n.has_tag_name((DAV_NS, "response")))
+    {
+        let href = match response
+            .children()
+            .find(|n| n.has_tag_name((DAV_NS, "href")))
+            .and_then(|n| n.text())
+        {
+            Some(h) => href_path(h.trim()),
+            None => continue,
+        };
+        let name = match href.trim_end_matches('/').strip_prefix(base_path) {
+            Some(rest) => rest.trim_matches('/').to_string(),
+            None => href
+                .trim_end_matches('/')
+                .rsplit('/')
+                .next()
+                .unwrap_or_default()
+                .to_string(),
+        };
+
+        let mut entry = DavEntry {
+            name,
+            size: None,
+            mtime: None,
+            is_collection: false,
+        };
+        for propstat in response
+            .children()
+            .filter(|n| n.has_tag_name((DAV_NS, "propstat")))
+        {
+            let ok = match propstat
+                .children()
+                .find(|n| n.has_tag_name((DAV_NS, "status")))
+                .and_then(|n| n.text())
+            {
+                Some(status) => status.contains(" 200 "),
+                None => true,
+            };
+            if !ok {
+                continue;
+            }
+            for prop in propstat
+                .descendants()
+                .filter(|n| n.tag_name().namespace() == Some(DAV_NS))
+            {
+                match prop.tag_name().name() {
+                    "getcontentlength" => {
+                        entry.size = prop.text().and_then(|t| t.trim().parse().ok());
+                    }
+                    "getlastmodified" => {
+                        entry.mtime = prop.text().map(|t| t.trim().to_string());
+                    }
+                    "collection" => entry.is_collection = true,
+                    _ => {}
+                }
+            }
+        }
+        ret.push(entry);
+    }
+    Ok(ret)
+}
+
+/// Send a PROPFIND for `url` with the given `Depth` header.
+pub fn propfind(client: &Client, url: &str, depth: &str) -> Result<Vec<DavEntry>, Box<dyn Error>> {
+    log::info!("PROPFIND {} depth {}", url, depth);
+    let result = match client
+        .request(Method::from_bytes(b"PROPFIND")?, url)
+        .header("Depth", depth)
+        .header(reqwest::header::CONTENT_TYPE, "application/xml")
+        .body(PROPFIND_BODY)
+        .send()
+    {
+        Ok(r) => r,
+        Err(e) => {
+            return Err(Box::new(MyError::Http {
+                url: url.to_string(),
+                status: None,
+                details: format!("Error sending the request: {:?}", e),
+            }));
+        }
+    };
+
+    let status = result.status();
+    let body = result.text().unwrap_or_default();
+    if status.as_u16() != 207 {
+        return Err(Box::new(MyError::Http {
+            url: url.to_string(),
+            status: Some(status.as_u16()),
+            details: format!("Error listing collection. body {}", body),
+        }));
+    }
+    parse_multistatus(&body, &href_path(url))
+}
+
+/// Parse an RFC 3230 `Digest` header, like `adler32=0a1b2c3d,md5=...`.
+fn parse_digest_header(header: &str) -> BTreeMap<String, String> {
+    let mut ret = BTreeMap::new();
+    for part in header.split(',') {
+        if let Some((alg, value)) = part.split_once('=') {
+            ret.insert(alg.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+    ret
+}
+
+/// Look up an object with HEAD, asking for checksums with `Want-Digest`,
+/// then use a `Depth: 0` PROPFIND to tell collections from files.
+///
+/// A missing object is not an error; it comes back with `exists: false`.
+pub fn stat(client: &Client, url: &str) -> Result<ObjectStat, Box<dyn Error>> {
+    log::info!("HEAD {}", url);
+    let result = match client.head(url).header("Want-Digest", WANT_DIGEST).send() {
+        Ok(r) => r,
+        Err(e) => {
+            return Err(Box::new(MyError::Http {
+                url: url.to_string(),
+                status: None,
+                details: format!("Error sending the request: {:?}", e),
+            }));
+        }
+    };
+
+    let mut ret = ObjectStat {
+        url: url.to_string(),
+        exists: false,
+        is_collection: false,
+        size: None,
+        mtime: None,
+        etag: None,
+        checksums: BTreeMap::new(),
+    };
+    let status = result.status();
+    if status.as_u16() == 404 {
+        return Ok(ret);
+    }
+    if !status.is_success() {
+        return Err(Box::new(MyError::Http {
+            url: url.to_string(),
+            status: Some(status.as_u16()),
+            details: "Error getting object metadata".into(),
+        }));
+    }
+
+    let headers = result.headers();
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+    };
+    ret.exists = true;
+    ret.size = header(reqwest::header::CONTENT_LENGTH).and_then(|v| v.parse().ok());
+    ret.mtime = header(reqwest::header::LAST_MODIFIED);
+    ret.etag = header(reqwest::header::ETAG);
+    if let Some(digest) = header(reqwest::header::HeaderName::from_static("digest")) {
+        ret.checksums = parse_digest_header(&digest);
+    }
+
+    match propfind(client, url, "0") {
+        Ok(entries) => {
+            if let Some(entry) = entries.first() {
+                ret.is_collection = entry.is_collection;
+                if ret.is_collection {
+                    ret.size = entry.size;
+                }
+                if ret.mtime.is_none() {
+                    ret.mtime = entry.mtime.clone();
+                }
+            }
+        }
+        Err(e) => log::warn!("Cannot tell if {} is a collection: {}", url, e),
+    }
+    Ok(ret)
+}
+
+/// Copy or move `url` to `destination` on the same server with WebDAV
+/// COPY or MOVE. Without `overwrite`, an existing destination is an error.
+pub fn copy(
+    client: &Client,
+    url: &str,
+    destination: &str,
+    is_move: bool,
+    overwrite: bool,
+) -> Result<(), Box<dyn Error>> {
+    let method: &[u8] = if is_move { b"MOVE" } else { b"COPY" };
+    log::info!(
+        "{} {} to {}",
+        String::from_utf8_lossy(method),
+        url,
+        destination
+    );
+    let result = match client
+        .request(Method::from_bytes(method)?, url)
+        .header("Destination", destination)
+        .header("Overwrite", if overwrite { "T" } else { "F" })
+        .send()
+    {
+        Ok(r) => r,
+        Err(e) => {
+            return Err(Box::new(MyError::Http {
+                url: url.to_string(),
+                status: None,
+                details: format!("Error sending the request: {:?}", e),
+            }));
+        }
+    };
+
+    let status = result.status();
+    if !status.is_success() {
+        return Err(Box::new(MyError::Http {
+            url: url.to_string(),
+            status: Some(status.as_u16()),
+            details: format!(
+                "Error copying to {}. body {}",
+                destination,
+                result.text().unwrap_or_default()
+            ),
+        }));
+    }
+    Ok(())
+}
+
+/// List the contents of a collection. When `recursive`, walk into
+/// sub-collections one level at a time, since many origins refuse
+/// `Depth: infinity`.
+pub fn list(client: &Client, url: &str, recursive: bool) -> Result<Vec<DavEntry>, Box<dyn Error>> {
+    let mut ret = Vec::new();
+    let mut pending = vec![String::new()];
+    while let Some(dir) = pending.pop() {
+        let dir_url = if dir.is_empty() {
+            url.to_string()
+        } else {
+            format!("{}/{}", url.trim_end_matches('/'), encode_path(&dir))
+        };
+        for mut entry in propfind(client, &dir_url, "1")? {
+            if entry.name.is_empty() {
+                continue;
+            }
+            if !dir.is_empty() {
+                entry.name = format!("{}/{}", dir, entry.name);
+            }
+            if recursive && entry.is_collection {
+                pending.push(entry.name.clone());
+            }
+            ret.push(entry);
+        }
+    }
+    ret.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use httpmock::prelude::*;
+
+    use super::*;
+    use crate::logging::test_logger;
+
+    fn multistatus(base: &str, entries: &[(&str, Option<u64>, bool)]) -> String {
+        let mut body = String::from(r#"<?xml version="1.0"?><D:multistatus xmlns:D="DAV:">"#);
+        for (name, size, is_collection) in entries {
+            body.push_str(&format!(
+                "<D:response><D:href>{base}{name}</D:href><D:propstat><D:prop>"
+            ));
+            if *is_collection {
+                body.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+            } else {
+                body.push_str("<D:resourcetype/>");
+            }
+            if let Some(size) = size {
+                body.push_str(&format!("<D:getcontentlength>{size}</D:getcontentlength>"));
+            }
+            body.push_str("<D:getlastmodified>Mon, 02 Jun 2025 10:00:00 GMT</D:getlastmodified>");
+            body.push_str(
+                "</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+            );
+        }
+        body.push_str("</D:multistatus>");
+        body
+    }
+
+    #[test]
+    fn test_parse_multistatus() {
+        let body = multistatus(
+            "/ns/dir",
+            &[
+                ("/", None, true),
+                ("/file%20one.txt", Some(12), false),
+                ("/sub/", None, true),
+            ],
+        );
+        let entries = parse_multistatus(&body, "/ns/dir").unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].name, "");
+        assert!(entries[0].is_collection);
+        assert_eq!(
+            entries[1],
+            DavEntry {
+                name: "file one.txt".into(),
+                size: Some(12),
+                mtime: Some("Mon, 02 Jun 2025 10:00:00 GMT".into()),
+                is_collection: false,
+            }
+        );
+        assert_eq!(entries[2].name, "sub");
+        assert!(entries[2].is_collection);
+    }
+
+    #[test]
+    fn test_encode_path() {
+        assert_eq!(encode_path("a dir/b#1.txt"), "a%20dir/b%231.txt");
+        assert_eq!(percent_decode(&encode_path("a dir/ü")), "a dir/ü");
+    }
+
+    #[test]
+    fn test_list_recursive() {
+        test_logger();
+
+        let server = MockServer::start();
+        let top = server.mock(|when, then| {
+            when.is_true(|req| req.method_str() == "PROPFIND")
+                .path("/ns/dir")
+                .header("Depth", "1");
+            then.status(207).body(multistatus(
+                "/ns/dir",
+                &[
+                    ("/", None, true),
+                    ("/a.txt", Some(1), false),
+                    ("/sub/", None, true),
+                ],
+            ));
+        });
+        let sub = server.mock(|when, then| {
+            when.is_true(|req| req.method_str() == "PROPFIND")
+                .path("/ns/dir/sub")
+                .header("Depth", "1");
+            then.status(207).body(multistatus(
+                "/ns/dir/sub",
+                &[("/", None, true), ("/b.txt", Some(2), false)],
+            ));
+        });
+
+        let client = Client::new();
+        let url = server.url("/ns/dir");
+
+        let entries = list(&client, &url, false).unwrap();
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["a.txt", "sub"]);
+        sub.assert_calls(0);
+
+        let entries = list(&client, &url, true).unwrap();
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["a.txt", "sub", "sub/b.txt"]);
+        top.assert_calls(2);
+        sub.assert_calls(1);
+    }
+
+    #[test]
+    fn test_parse_digest_header() {
+        let checksums = parse_digest_header("ADLER32=0a1b2c3d, md5=HUXZLQLMuI/KZ5KDcJPcOA==");
+        assert_eq!(checksums.get("adler32").unwrap(), "0a1b2c3d");
+        assert_eq!(checksums.get("md5").unwrap(), "HUXZLQLMuI/KZ5KDcJPcOA==");
+    }
+
+    #[test]
+    fn test_stat() {
+        test_logger();
+
+        let server = MockServer::start();
+        let head = server.mock(|when, then| {
+            when.method(httpmock::Method::HEAD)
+                .path("/ns/file.bin")
+                .header_exists("Want-Digest");
+            then.status(200)
+                .header("Content-Length", "12")
+                .header("Last-Modified", "Mon, 02 Jun 2025 10:00:00 GMT")
+                .header("ETag", "\"abc\"")
+                .header("Digest", "adler32=0a1b2c3d");
+        });
+        server.mock(|when, then| {
+            when.is_true(|req| req.method_str() == "PROPFIND")
+                .path("/ns/file.bin")
+                .header("Depth", "0");
+            then.status(207)
+                .body(multistatus("/ns/file.bin", &[("", Some(12), false)]));
+        });
+        server.mock(|when, then| {
+            when.method(httpmock::Method::HEAD).path("/ns/missing");
+            then.status(404);
+        });
+        server.mock(|when, then| {
+            when.method(httpmock::Method::HEAD).path("/ns/secret");
+            then.status(403);
+        });
+
+        let client = Client::new();
+        let stat_result = stat(&client, &server.url("/ns/file.bin")).unwrap();
+        head.assert();
+        assert!(stat_result.exists);
+        assert!(!stat_result.is_collection);
+        assert_eq!(stat_result.size, Some(12));
+        assert_eq!(stat_result.etag.as_deref(), Some("\"abc\""));
+        assert_eq!(stat_result.checksums.get("adler32").unwrap(), "0a1b2c3d");
+
+        let stat_result = stat(&client, &server.url("/ns/missing")).unwrap();
+        assert!(!stat_result.exists);
+
+        let err = stat(&client, &server.url("/ns/secret")).unwrap_err();
+        match err.downcast_ref::<MyError>() {
+            Some(MyError::Http { status, .. }) => assert_eq!(*status, Some(403)),
+            _ => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_copy() {
+        test_logger();
+
+        let server = MockServer::start();
+        let dest = server.url("/ns/b.txt");
+        let dest_header = dest.clone();
+        let mock = server.mock(move |when, then| {
+            when.is_true(|req| req.method_str() == "MOVE")
+                .path("/ns/a.txt")
+                .header("Destination", dest_header)
+                .header("Overwrite", "F");
+            then.status(201);
+        });
+        server.mock(|when, then| {
+            when.is_true(|req| req.method_str() == "COPY")
+                .path("/ns/a.txt");
+            then.status(412);
+        });
+
+        let client = Client::new();
+        copy(&client, &server.url("/ns/a.txt"), &dest, true, false).unwrap();
+        mock.assert();
+
+        let err = copy(&client, &server.url("/ns/a.txt"), &dest, false, false).unwrap_err();
+        match err.downcast_ref::<MyError>() {
+            Some(MyError::Http { status, .. }) => assert_eq!(*status, Some(412)),
+            _ => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_propfind_error() {
+        test_logger();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/ns/missing");
+            then.status(404);
+        });
+
+        let err = propfind(&Client::new(), &server.url("/ns/missing"), "1").unwrap_err();
+        match err.downcast_ref::<MyError>() {
+            Some(MyError::Http { status, .. }) => assert_eq!(*status, Some(404)),
+            _ => panic!("unexpected error {:?}", err),
+        }
+    }
+}